@@ -1,120 +1,317 @@
 #define_import_path voxels::ray_march
 
-#import bevy_pbr::mesh_functions::get_model_matrix
 #import bevy_render::view::View
-#import bevy_pbr::mesh_bindings::mesh
+
+struct VoxelRegion {
+    // world position of the region's corner, in voxels
+    origin: vec3<i32>,
+    chunk_size: u32,
+    // every non-empty voxel is in bounds_min..bounds_max, relative to origin
+    bounds_min: vec3<u32>,
+    // chunks along each edge of the region
+    region_size: u32,
+    bounds_max: vec3<u32>,
+    // lux next to a voxel lit at `MAX_LIGHT_LEVEL`
+    light_illuminance: f32,
+}
 
 @group(2) @binding(0)
-var chunk_texture: texture_3d<f32>;
+var<uniform> region: VoxelRegion;
+// one value per chunk, indexed by chunk position in z, y, x order. either a pointer to the chunk's root node in
+// `region_nodes`, or a leaf if the whole chunk is one id.
+@group(2) @binding(1)
+var<storage, read> region_chunks: array<u32>;
+// `Octree::extend_raw_data` for every chunk in the region: 8 values per node, encoded like `OctreeValue`
+@group(2) @binding(2)
+var<storage, read> region_nodes: array<u32>;
+
+struct VoxelMaterial {
+    base_color: vec4<f32>,
+    emissive: vec4<f32>,
+    perceptual_roughness: f32,
+    metallic: f32,
+    ior: f32,
+}
+
+// `VoxelPalette`, indexed by voxel id. Empty voxels are never shaded, so entry 0 holds the material for ids past the end.
+@group(2) @binding(3)
+var<storage, read> palette: array<VoxelMaterial>;
+
+// for each chunk, where its light levels start in the rest of the array, or 0xffffffff if the chunk is dark. the levels
+// are one value per voxel, ordered by z, then y, then x, with red, green, and blue light from emissive voxels in the
+// low three bytes and skylight in the high byte.
+@group(2) @binding(4)
+var<storage, read> region_light: array<u32>;
+
+// must match `MAX_LIGHT_LEVEL` in light.rs
+const MAX_LIGHT_LEVEL: f32 = 15.0;
+
+// The light hitting the face at `world_pos`, from 0 to 1 per channel, with skylight in w. That's the light in the voxel
+// the face looks out into. Other regions can't be read from here, so faces on the edge of the region use the
+// light stored in the voxel behind them instead.
+fn voxel_light(world_pos: vec3<f32>, world_normal: vec3<f32>) -> vec4<f32> {
+    var pos = vec3<i32>(floor(world_pos + world_normal * 0.5)) - region.origin;
+    if (any(pos < vec3(0)) || any(pos >= vec3(i32(region.chunk_size * region.region_size)))) {
+        pos = vec3<i32>(floor(world_pos - world_normal * 0.5)) - region.origin;
+    }
+
+    let voxel = vec3<u32>(clamp(pos, vec3(0), vec3(i32(region.chunk_size * region.region_size) - 1)));
+    let chunk = voxel / region.chunk_size;
+    let start = region_light[(chunk.z * region.region_size + chunk.y) * region.region_size + chunk.x];
+    if (start == 0xffffffffu) {
+        return vec4(0.0);
+    }
+    let local = voxel % region.chunk_size;
+    let value = region_light[start + (local.z * region.chunk_size + local.y) * region.chunk_size + local.x];
+    return vec4<f32>(vec4(value, value >> 8u, value >> 16u, value >> 24u) & vec4(0xffu)) / MAX_LIGHT_LEVEL;
+}
+
+fn voxel_material(id: u32) -> VoxelMaterial {
+    if (id >= arrayLength(&palette)) {
+        return palette[0];
+    }
+    return palette[id];
+}
+
+fn is_translucent(id: u32) -> bool {
+    return voxel_material(id).base_color.a < 1.0;
+}
 
 struct RayMarchOutput {
+    // the first voxel the ray hit, translucent or not. this is the depth the region gets.
     world_pos: vec4<f32>,
     clip_pos: vec4<f32>,
     world_normal: vec3<f32>,
+    voxel_id: u32,
+    // the opaque voxel behind any translucent ones, or the same as the first voxel if it's opaque. `opaque_id` is 0 if
+    // the ray left the region without hitting one.
+    opaque_pos: vec4<f32>,
+    opaque_normal: vec3<f32>,
+    opaque_id: u32,
+    // of the opaque voxel. 1 for no occlusion, down to 0 in fully enclosed corners
+    ambient_occlusion: f32,
+    // how much of the light from behind the translucent voxels makes it through them, 1 if there weren't any
+    transmittance: f32,
+    // the color the translucent voxels add along the way, already scaled by how much light they absorbed
+    medium_color: vec3<f32>,
+}
+
+struct OctreeLeaf {
+    id: u32,
+    min: vec3<u32>,
+    size: u32,
+}
+
+fn node_value(node_idx: u32, pos: vec3<u32>, quadrant_size: u32) -> u32 {
+    let quadrant = (pos / quadrant_size) & vec3(1u);
+    return region_nodes[node_idx * 8u + quadrant.z * 4u + quadrant.y * 2u + quadrant.x];
 }
 
-// Based on A Fast Voxel Traversal Algorithm for Ray Tracing (http://www.cse.yorku.ca/~amana/research/grid.pdf) with
-// more concise code taken from Branchless Voxel Raycasting (https://www.shadertoy.com/view/4dX3zl)
-fn ray_march(instance_index: u32, in_world_position: vec3<f32>, view: View, front_facing: bool) -> RayMarchOutput {
-    let forward = normalize(in_world_position - view.world_position);
-    let step = sign(forward);
-
-    let chunk_origin = get_model_matrix(instance_index)[3].xyz;
-    var chunk_pos = vec3(0.0);
-    if (front_facing) {
-      chunk_pos = in_world_position - chunk_origin;
-    } else {
-      chunk_pos = view.world_position - chunk_origin;
+// Walks down from the root node of the chunk containing `pos` to the leaf containing `pos`. The low bit of each value is
+// set for pointers to another node, and the remaining bits hold either the node index or the voxel id.
+fn octree_leaf(pos: vec3<u32>) -> OctreeLeaf {
+    let chunk = pos / region.chunk_size;
+    var value = region_chunks[(chunk.z * region.region_size + chunk.y) * region.region_size + chunk.x];
+
+    var quadrant_size = region.chunk_size;
+    while ((value & 1u) == 1u && quadrant_size > 1u) {
+        quadrant_size /= 2u;
+        value = node_value(value >> 1u, pos, quadrant_size);
     }
 
-    let t_delta = abs(1.0 / forward);
+    var leaf: OctreeLeaf;
+    leaf.id = value >> 1u;
+    leaf.min = pos & vec3(~(quadrant_size - 1u));
+    leaf.size = quadrant_size;
+    return leaf;
+}
 
-    var voxel_idx: vec3<f32> = min(floor(chunk_pos), vec3(15.0));
-    var pos_in_voxel = chunk_pos - voxel_idx;
-    var t_max = (step * (chunk_origin + voxel_idx - view.world_position) + (step * 0.5) + 0.5) * t_delta;
-    var world_normal = vec3<f32>(vec3(16.0) == chunk_pos) - vec3<f32>(vec3(0.0) == chunk_pos);
+#ifdef VOXEL_AMBIENT_OCCLUSION
+// `pos` is relative to the region. Voxels outside of it count as empty, since other regions can't be read from here.
+fn is_solid(pos: vec3<f32>) -> bool {
+    if (any(pos < vec3<f32>(region.bounds_min)) || any(pos >= vec3<f32>(region.bounds_max))) {
+        return false;
+    }
+    return octree_leaf(vec3<u32>(pos)).id != 0u;
+}
 
-    while (true) {
-        let voxel = textureLoad(chunk_texture, vec3<i32>(voxel_idx), 0);
-        if (voxel.x > 0.0) {
-            let voxel_world_pos = chunk_origin + voxel_idx;
-            let world_pos = vec4(intersect_ray_aabb(view.world_position, forward, voxel_world_pos, voxel_world_pos + 1.0), 1.0);
-            let clip_pos = view.view_proj * world_pos;
-
-            var out: RayMarchOutput;
-            out.world_pos = world_pos;
-            out.clip_pos = clip_pos;
-            out.world_normal = world_normal;
-            return out;
-        }
+// how much a corner of a face is occluded by the voxels next to it, from 0 to 3
+fn corner_occlusion(side_a: bool, side_b: bool, corner: bool) -> f32 {
+    // the corner voxel can't be seen past two sides, so it doesn't count
+    if (side_a && side_b) {
+        return 3.0;
+    }
+    return f32(side_a) + f32(side_b) + f32(corner);
+}
 
-        let mask = vec3<f32>(t_max.xyz <= min(t_max.yzx, t_max.zxy));
-        t_max += mask * t_delta;
-        voxel_idx += mask * step;
-        world_normal = -(mask * step);
-        if (
-            voxel_idx.x < 0.0 || voxel_idx.x >= 16.0
-            || voxel_idx.y < 0.0 || voxel_idx.y >= 16.0
-            || voxel_idx.z < 0.0 || voxel_idx.z >= 16.0
-        ) {
-            discard;
-        }
+// Each corner of the face is darkened by the voxels touching it on the outside of the face, and the corners are
+// blended across the face by `hit_pos`.
+fn face_ambient_occlusion(voxel_idx: vec3<f32>, normal: vec3<f32>, hit_pos: vec3<f32>) -> f32 {
+    // the two axes along the face
+    let u = abs(normal.yzx);
+    let v = abs(normal.zxy);
+    let front = voxel_idx + normal;
+
+    let u_neg = is_solid(front - u);
+    let u_pos = is_solid(front + u);
+    let v_neg = is_solid(front - v);
+    let v_pos = is_solid(front + v);
+    let occlusion_00 = corner_occlusion(u_neg, v_neg, is_solid(front - u - v));
+    let occlusion_10 = corner_occlusion(u_pos, v_neg, is_solid(front + u - v));
+    let occlusion_01 = corner_occlusion(u_neg, v_pos, is_solid(front - u + v));
+    let occlusion_11 = corner_occlusion(u_pos, v_pos, is_solid(front + u + v));
+
+    let face_pos = clamp(hit_pos - voxel_idx, vec3(0.0), vec3(1.0));
+    let face_u = dot(face_pos, u);
+    let face_v = dot(face_pos, v);
+    let occlusion = mix(mix(occlusion_00, occlusion_10, face_u), mix(occlusion_01, occlusion_11, face_u), face_v);
+
+    // fully enclosed corners still get some ambient light, or they'd turn black
+    return 1.0 - occlusion / 3.0 * 0.8;
+}
+#endif
+
+// Once a ray has bent this many times it goes straight, so total internal reflection can't trap it
+const MAX_REFRACTIONS: u32 = 8u;
 
-        // if (t_max.x < t_max.y) {
-        //     if (t_max.x < t_max.z) {
-        //         voxel_idx.x += step.x;
-        //         t_max.x += t_delta.x;
-        //         world_normal = vec3<f32>(-step.x, 0.0, 0.0);
-        //         if (voxel_idx.x < 0.0 || voxel_idx.x >= 16.0) {
-        //             discard;
-        //         }
-        //     } else {
-        //         voxel_idx.z += step.z;
-        //         t_max.z += t_delta.z;
-        //         world_normal = vec3<f32>(0.0, 0.0, -step.z);
-        //         if (voxel_idx.z < 0.0 || voxel_idx.z >= 16.0) {
-        //             discard;
-        //         }
-        //     }
-        // } else {
-        //     if (t_max.y < t_max.z) {
-        //         voxel_idx.y += step.y;
-        //         t_max.y += t_delta.y;
-        //         world_normal = vec3<f32>(0.0, -step.y, 0.0);
-        //         if (voxel_idx.y < 0.0 || voxel_idx.y >= 16.0) {
-        //             discard;
-        //         }
-        //     } else {
-        //         voxel_idx.z += step.z;
-        //         t_max.z += t_delta.z;
-        //         world_normal = vec3<f32>(0.0, 0.0, -step.z);
-        //         if (voxel_idx.z < 0.0 || voxel_idx.z >= 16.0) {
-        //             discard;
-        //         }
-        //     }
-        // }
+// Based on A Fast Voxel Traversal Algorithm for Ray Tracing (http://www.cse.yorku.ca/~amana/research/grid.pdf), but
+// instead of stepping one voxel at a time, each step skips the whole octree leaf the ray is in, so empty quadrants cost
+// a single iteration no matter how large they are. The ray crosses freely between the chunks of a region.
+//
+// Translucent voxels don't stop the ray. It bends by the change in `ior` whenever it crosses into a different
+// material, loses some of its light for every voxel of distance it travels through them, and keeps going until it hits
+// an opaque voxel or leaves the region.
+fn ray_march(in_world_position: vec3<f32>, view: View) -> RayMarchOutput {
+    var ray_origin = view.world_position;
+    var forward = normalize(in_world_position - view.world_position);
+    // orthographic views, like directional light shadows, have parallel rays that start on the view plane
+    if (view.projection[3].w == 1.0) {
+        forward = normalize(-view.view[2].xyz);
+        ray_origin = in_world_position - forward * dot(in_world_position - view.world_position, forward);
     }
-    
+    var step = sign(forward);
+    // axes the ray doesn't move along never get crossed
+    var inv_dir = select(1.0 / forward, vec3(1e30), step == vec3(0.0));
+
+    var origin = ray_origin - vec3<f32>(region.origin);
+    // only the part of the region with something in it needs to be marched
+    let bounds_min = vec3<f32>(region.bounds_min);
+    let bounds_max = vec3<f32>(region.bounds_max);
+
+    // find where the ray enters the bounds, or start at the camera if it's inside
+    let t_planes_near = min((bounds_min - origin) * inv_dir, (bounds_max - origin) * inv_dir);
+    let t_near = max(max(t_planes_near.x, t_planes_near.y), t_planes_near.z);
+#ifdef DEPTH_CLAMP_ORTHO
+    // directional lights still need shadows from voxels behind their near plane, which get clamped onto it
+    var t = t_near;
+#else
+    var t = max(t_near, 0.0);
+#endif
+    var world_normal = -step * vec3<f32>(t_planes_near == vec3(t));
+
+    var voxel_idx = clamp(floor(origin + forward * t), bounds_min, bounds_max - 1.0);
+    var prev_voxel_idx = voxel_idx;
+    // 1 on axes where the ray leaves a cell through its max side, 0 where it leaves through its min side
+    var exit_side = vec3<f32>(step > vec3(0.0));
+
     var out: RayMarchOutput;
-    out.world_pos = vec4(0.0);
-    out.clip_pos = vec4(0.0);
-    out.world_normal = vec3(0.0);
-    return out;
-}
+    out.voxel_id = 0u;
+    out.opaque_id = 0u;
+    out.ambient_occlusion = 1.0;
+    out.transmittance = 1.0;
+    out.medium_color = vec3(0.0);
+
+    // the translucent id the ray is currently inside of, or 0 for empty space
+    var medium = 0u;
+    var medium_ior = 1.0;
+    var refractions = 0u;
+    while (true) {
+        let leaf = octree_leaf(vec3<u32>(voxel_idx));
+        if (leaf.id != medium) {
+            let world_pos = vec4(ray_origin + forward * t, 1.0);
+            if (out.voxel_id == 0u) {
+                out.world_pos = world_pos;
+                out.clip_pos = view.view_proj * world_pos;
+                out.world_normal = world_normal;
+                out.voxel_id = leaf.id;
+            }
 
-fn intersect_ray_aabb(ray_origin: vec3<f32>, ray_dir: vec3<f32>, aabb_min: vec3<f32>, aabb_max: vec3<f32>) -> vec3<f32> {
-    let inv_dir = 1.0 / ray_dir;
-    let t_min = (aabb_min - ray_origin) * inv_dir;
-    let t_max = (aabb_max - ray_origin) * inv_dir;
+            if (leaf.id != 0u && !is_translucent(leaf.id)) {
+                out.opaque_pos = world_pos;
+                out.opaque_normal = world_normal;
+                out.opaque_id = leaf.id;
+#ifdef VOXEL_AMBIENT_OCCLUSION
+                // rays that start inside a voxel don't hit a face
+                if (any(world_normal != vec3(0.0))) {
+                    out.ambient_occlusion = face_ambient_occlusion(voxel_idx, world_normal, origin + forward * t);
+                }
+#endif
+                return out;
+            }
 
-    let t1 = min(t_min, t_max);
-    let t2 = max(t_min, t_max);
-    let t_near = max(max(t1.x, t1.y), t1.z);
-    let t_far = min(min(t2.x, t2.y), t2.z);
+            let next_ior = select(1.0, voxel_material(leaf.id).ior, leaf.id != 0u);
+            // rays that start inside a voxel don't cross a face, so they don't bend
+            if (next_ior != medium_ior && any(world_normal != vec3(0.0)) && refractions < MAX_REFRACTIONS) {
+                refractions += 1u;
+                var new_forward = refract(forward, world_normal, medium_ior / next_ior);
+                if (all(new_forward == vec3(0.0))) {
+                    // total internal reflection, so the ray stays where it was
+                    new_forward = reflect(forward, world_normal);
+                    voxel_idx = prev_voxel_idx;
+                } else {
+                    medium = leaf.id;
+                    medium_ior = next_ior;
+                }
 
-    let intersection = ray_origin + ray_dir * t_near;
-    let no_intersection = vec3<f32>(-1.0);
-    let result = mix(no_intersection, intersection, f32(t_near <= t_far && t_near >= 0.0));
+                // restart the ray from the face it bent at
+                ray_origin = world_pos.xyz;
+                origin = ray_origin - vec3<f32>(region.origin);
+                forward = normalize(new_forward);
+                step = sign(forward);
+                inv_dir = select(1.0 / forward, vec3(1e30), step == vec3(0.0));
+                exit_side = vec3<f32>(step > vec3(0.0));
+                t = 0.0;
+                continue;
+            }
+            medium = leaf.id;
+            medium_ior = next_ior;
+        }
 
-    return result;
+        let leaf_min = vec3<f32>(leaf.min);
+        let leaf_size = f32(leaf.size);
+        // inv_dir is huge on axes the ray runs parallel to, which would put their planes far behind the ray instead of
+        // never reaching them. step is set again whenever the ray bends, so this covers restarted rays too.
+        let t_planes_far = select(
+            (leaf_min + exit_side * leaf_size - origin) * inv_dir,
+            vec3(1e30),
+            step == vec3(0.0),
+        );
+        let t_exit = min(min(t_planes_far.x, t_planes_far.y), t_planes_far.z);
+        let mask = vec3<f32>(t_planes_far == vec3(t_exit));
+        world_normal = -(mask * step);
+
+        if (medium != 0u) {
+            // alpha is how much light one voxel of the material absorbs
+            let material = voxel_material(medium);
+            let leaf_transmittance = pow(1.0 - material.base_color.a, t_exit - t);
+            out.medium_color += out.transmittance * (1.0 - leaf_transmittance) * material.base_color.rgb;
+            out.transmittance *= leaf_transmittance;
+        }
+        t = t_exit;
+
+        // step out of the leaf on the axes we crossed, and stay inside it on the others
+        let crossed = leaf_min + mix(vec3(-1.0), vec3(leaf_size), exit_side);
+        let inside = clamp(floor(origin + forward * t), leaf_min, leaf_min + leaf_size - 1.0);
+        prev_voxel_idx = voxel_idx;
+        voxel_idx = mix(inside, crossed, mask);
+        if (any(voxel_idx < bounds_min) || any(voxel_idx >= bounds_max)) {
+            // whatever is behind the translucent voxels is drawn by something else
+            if (out.voxel_id == 0u) {
+                discard;
+            }
+            return out;
+        }
+    }
+
+    return out;
 }
@@ -1,30 +1,79 @@
 #import bevy_pbr::mesh_view_bindings::view
+#import bevy_pbr::mesh_bindings::mesh
 #import bevy_pbr::forward_io::VertexOutput
 #import bevy_pbr::pbr_functions::pbr
 #import bevy_pbr::pbr_functions::apply_pbr_lighting
 #import bevy_pbr::pbr_types::pbr_input_new
 #import bevy_core_pipeline::tonemapping::tone_mapping
-#import voxels::ray_march::ray_march
+#import bevy_pbr::utils::PI
+#import voxels::ray_march::{ray_march, region, voxel_light, voxel_material, VoxelMaterial}
 
 struct FragmentOutput {
     @builtin(frag_depth) depth: f32,
     @location(0) color: vec4<f32>,
 }
 
-@fragment
-fn fragment(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
-    var res = ray_march(in.instance_index, in.world_position.xyz, view, front_facing);
+fn shade(
+    in: VertexOutput,
+    world_pos: vec4<f32>,
+    world_normal: vec3<f32>,
+    material: VoxelMaterial,
+    base_color: vec3<f32>,
+) -> vec3<f32> {
+    let clip_pos = view.view_proj * world_pos;
 
     var pbr = pbr_input_new();
-    pbr.frag_coord = vec4(in.position.xy, -res.clip_pos.z, 1.0);
-    pbr.world_position = res.world_pos;
-    pbr.world_normal = res.world_normal;
-    pbr.N = res.world_normal;
-    pbr.V = normalize(view.world_position - res.world_pos.xyz);
-    pbr.material.base_color = vec4(0.0, 1.0, 0.0, 1.0);
+    pbr.frag_coord = vec4(in.position.xy, -clip_pos.z, 1.0);
+    // receive shadows, unless the region has `NotShadowReceiver`
+    pbr.flags = mesh[in.instance_index].flags;
+    pbr.world_position = world_pos;
+    pbr.world_normal = world_normal;
+    pbr.N = world_normal;
+    pbr.V = normalize(view.world_position - world_pos.xyz);
+
+    pbr.material.base_color = vec4(base_color, 1.0);
+    pbr.material.perceptual_roughness = material.perceptual_roughness;
+    pbr.material.metallic = material.metallic;
+
+    // light from emissive voxels only lights up surfaces near them, so it's added on top like emissive. levels fall off
+    // linearly, which looks too flat in linear color, so they're squared.
+    let light = voxel_light(world_pos.xyz, world_normal);
+    let voxel_light = base_color * light.rgb * light.rgb * region.light_illuminance / PI;
+    pbr.material.emissive = vec4(material.emissive.rgb + voxel_light, material.emissive.a);
+    // ambient light comes from the sky, so it fades out the same way in caves
+    let sky = light.w * light.w;
+    pbr.diffuse_occlusion = vec3(sky);
+    pbr.specular_occlusion = sky;
+    return apply_pbr_lighting(pbr).rgb;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    var res = ray_march(in.world_position.xyz, view);
+
+    // light from the opaque voxel, or nothing if the ray only went through translucent ones
+    var color = vec3(0.0);
+    var alpha = 0.0;
+    if (res.opaque_id != 0u) {
+        let material = voxel_material(res.opaque_id);
+        // voxel ambient occlusion is there to make shapes readable rather than to be physically correct, so it darkens
+        // direct light too instead of only going in `diffuse_occlusion`
+        let base_color = material.base_color.rgb * res.ambient_occlusion;
+        color = shade(in, res.opaque_pos, res.opaque_normal, material, base_color);
+        alpha = 1.0;
+    }
+
+    // the translucent voxels in front are lit as one surface where the ray entered them, with their colors mixed
+    if (res.transmittance < 1.0) {
+        let opacity = 1.0 - res.transmittance;
+        let surface = shade(in, res.world_pos, res.world_normal, voxel_material(res.voxel_id), res.medium_color / opacity);
+        color = surface * opacity + color * res.transmittance;
+        alpha = opacity + alpha * res.transmittance;
+    }
 
     var out: FragmentOutput;
-    out.color = tone_mapping(apply_pbr_lighting(pbr), view.color_grading);
+    // blending multiplies by alpha again, so undo it here
+    out.color = tone_mapping(vec4(color / max(alpha, 1e-5), alpha), view.color_grading);
     out.depth = res.clip_pos.z / res.clip_pos.w;
     return out;
 }
@@ -1,5 +1,4 @@
 #import bevy_pbr::mesh_view_bindings::view
-#import bevy_pbr::utils::coords_to_viewport_uv
 #import voxels::ray_march::ray_march
 #import bevy_pbr::prepass_io::VertexOutput
 
@@ -20,16 +19,10 @@ struct FragmentOutput {
     @builtin(frag_depth) frag_depth: f32,
 }
 
-@group(1) @binding(0)
-var chunk_texture: texture_3d<f32>;
-
 @fragment
-fn fragment(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
-    let viewport_uv = coords_to_viewport_uv(in.position.xy, view.viewport);
-    let viewport_ndc = viewport_uv * 2.0 - 1.0;
-    let clip_pos = vec4(viewport_ndc.x, -viewport_ndc.y, in.position.z, 1.0);
-    let world_pos = view.inverse_view_proj * clip_pos;
-    var res = ray_march(in.instance_index, (world_pos / world_pos.w).xyz, view, front_facing);
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    // start from the same position as the main pass, so both get exactly the same depth
+    var res = ray_march(in.world_position.xyz, view);
 
     var out: FragmentOutput;
 #ifdef NORMAL_PREPASS
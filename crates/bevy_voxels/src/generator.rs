@@ -0,0 +1,86 @@
+use crate::octree::Octree;
+use bevy::prelude::*;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+
+/// Fills in the voxels of chunks as they're loaded
+///
+/// Chunks are generated again every time they're reloaded, so the output must only depend on the generator's own state
+/// (like its seed) and the chunk position.
+pub trait ChunkGenerator: Send + Sync + 'static {
+	/// `octree` starts out empty. `chunk_position` is in chunks, so the voxel at local position `pos` is at
+	/// `chunk_position * octree.size() + pos` in the world.
+	fn generate(&self, chunk_position: IVec3, octree: &mut Octree);
+}
+
+/// Rolling hills with caves underneath
+///
+/// Everything is sampled in world coordinates, so terrain lines up across chunk borders.
+pub struct TerrainGenerator {
+	height: Fbm<Perlin>,
+	caves: Fbm<Perlin>,
+}
+impl TerrainGenerator {
+	pub const STONE: u32 = 1;
+	pub const DIRT: u32 = 2;
+	pub const GRASS: u32 = 3;
+
+	/// height of the terrain where the height noise is 0
+	const BASE_HEIGHT: f64 = 8.0;
+	/// how far the terrain goes above and below `BASE_HEIGHT`
+	const HEIGHT_RANGE: f64 = 24.0;
+	const DIRT_DEPTH: i32 = 3;
+	/// caves are carved where the cave noise is above this, so higher values mean fewer, smaller caves
+	const CAVE_THRESHOLD: f64 = 0.35;
+	/// keep caves from breaking through the surface everywhere
+	const CAVE_MIN_DEPTH: i32 = 6;
+
+	pub fn new(seed: u32) -> Self {
+		Self {
+			height: Fbm::new(seed).set_octaves(4).set_frequency(1.0 / 128.0),
+			caves: Fbm::new(seed.wrapping_add(1)).set_octaves(2).set_frequency(1.0 / 32.0),
+		}
+	}
+
+	fn height(&self, x: i32, z: i32) -> i32 {
+		let noise = self.height.get([x as f64, z as f64]);
+		(Self::BASE_HEIGHT + noise * Self::HEIGHT_RANGE).floor() as i32
+	}
+
+	fn is_cave(&self, pos: IVec3) -> bool {
+		self.caves.get([pos.x as f64, pos.y as f64, pos.z as f64]) > Self::CAVE_THRESHOLD
+	}
+}
+impl Default for TerrainGenerator {
+	fn default() -> Self {
+		Self::new(0)
+	}
+}
+impl ChunkGenerator for TerrainGenerator {
+	fn generate(&self, chunk_position: IVec3, octree: &mut Octree) {
+		let size = octree.size();
+		let chunk_origin = chunk_position * size as i32;
+
+		for z in 0..size {
+			for x in 0..size {
+				let height = self.height(chunk_origin.x + x as i32, chunk_origin.z + z as i32);
+
+				// only go as high as the surface, and skip the column entirely if it's below the chunk
+				let column_top = (height - chunk_origin.y + 1).min(size as i32);
+				for y in 0..column_top.max(0) as u32 {
+					let world_pos = chunk_origin + UVec3::new(x, y, z).as_ivec3();
+					let depth = height - world_pos.y;
+					if depth >= Self::CAVE_MIN_DEPTH && self.is_cave(world_pos) {
+						continue;
+					}
+
+					let id = match depth {
+						0 => Self::GRASS,
+						depth if depth <= Self::DIRT_DEPTH => Self::DIRT,
+						_ => Self::STONE,
+					};
+					octree.set_voxel(UVec3::new(x, y, z), id);
+				}
+			}
+		}
+	}
+}
@@ -1,46 +1,218 @@
+pub mod generator;
+pub mod light;
+pub mod math;
 pub mod octree;
+pub mod palette;
+pub mod vox;
+pub mod world;
 
-mod math;
+mod region;
 
 use bevy::{
-	math::vec3,
-	pbr::{MaterialPipeline, MaterialPipelineKey},
+	math::Affine3A,
 	prelude::*,
-	reflect::TypePath,
-	render::{
-		mesh::MeshVertexBufferLayout,
-		render_asset::RenderAssetUsages,
-		render_resource::{
-			AsBindGroup, Extent3d, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError, TextureDimension,
-			TextureFormat,
-		},
-		texture::{ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
-	},
-	utils::HashMap,
+	render::primitives::{Aabb, Frustum},
+	tasks::{block_on, poll_once, AsyncComputeTaskPool, Task},
+	utils::{HashMap, HashSet},
 };
-use std::sync::{Arc, Weak};
+use generator::{ChunkGenerator, TerrainGenerator};
+use light::{update_lights, ChunkLight, DirtyLights};
+use octree::{Occupancy, Octree};
+use palette::{prepare_palette, PaletteBuffer, VoxelPalette};
+use region::{upload_regions, RegionBox, RegionMaterial, Regions};
+use std::{
+	cmp::Reverse,
+	collections::BinaryHeap,
+	sync::{Arc, Weak},
+};
+use vox::{VoxLoader, VoxModel};
 
-pub struct VoxelRenderPlugin;
+pub struct VoxelRenderPlugin {
+	/// Fills in chunks as they're loaded. Defaults to a [`TerrainGenerator`] with seed 0.
+	pub generator: Arc<dyn ChunkGenerator>,
+	pub budget: ChunkLoadBudget,
+	/// Edge length of every chunk, in voxels. Must be a power of 2 and at least 2. Defaults to 16.
+	///
+	/// [`ChunkLoader`] shapes are measured in chunks, so bigger chunks also load a bigger area.
+	pub chunk_size: u32,
+	/// Chunks are drawn in cubes of `region_size`³ chunks, which rays can cross without leaving the shader. Bigger
+	/// regions mean less overdraw, but any change to a chunk, even a single voxel, uploads every chunk in its region
+	/// again. Defaults to 4.
+	pub region_size: u32,
+	pub lod: ChunkLod,
+	/// Darkens the corners of voxel faces next to other voxels, which makes flat areas of the same color much easier to
+	/// read. Defaults to true.
+	pub ambient_occlusion: bool,
+	/// How brightly voxels with a [`light`](palette::VoxelMaterial::light) light up their surroundings, as the
+	/// illuminance in lux right next to one at [`MAX_LIGHT_LEVEL`](light::MAX_LIGHT_LEVEL). Defaults to 2000.
+	pub light_illuminance: f32,
+}
+impl VoxelRenderPlugin {
+	pub fn with_generator(generator: impl ChunkGenerator) -> Self {
+		Self {
+			generator: Arc::new(generator),
+			budget: ChunkLoadBudget::default(),
+			chunk_size: 16,
+			region_size: 4,
+			lod: ChunkLod::default(),
+			ambient_occlusion: true,
+			light_illuminance: 2000.0,
+		}
+	}
+}
+impl Default for VoxelRenderPlugin {
+	fn default() -> Self {
+		Self::with_generator(TerrainGenerator::default())
+	}
+}
 impl Plugin for VoxelRenderPlugin {
 	fn build(&self, app: &mut App) {
-		app.add_plugins(MaterialPlugin::<ChunkMaterial>::default())
+		assert!(
+			self.chunk_size >= 2 && self.chunk_size.is_power_of_two(),
+			"chunk size must be a power of 2 and at least 2"
+		);
+		assert!(self.region_size >= 1, "region size must be at least 1");
+
+		app.add_plugins(MaterialPlugin::<RegionMaterial>::default())
+			.insert_resource(Generator(self.generator.clone()))
+			.insert_resource(self.budget)
+			.insert_resource(self.lod)
+			.insert_resource(ChunkSize(self.chunk_size))
+			.insert_resource(AmbientOcclusion(self.ambient_occlusion))
+			.insert_resource(LightIlluminance(self.light_illuminance))
+			.insert_resource(Regions::new(self.region_size))
+			.init_resource::<DirtyLights>()
+			.init_resource::<VoxelPalette>()
+			.init_resource::<PaletteBuffer>()
+			.init_asset::<VoxModel>()
+			.init_asset_loader::<VoxLoader>()
 			.add_systems(Startup, setup)
-			.add_systems(Update, load_chunks);
+			.add_systems(Update, (finish_chunks, load_chunks).chain())
+			.add_systems(
+				PostUpdate,
+				(prepare_palette, update_summaries, update_lights, upload_regions).chain(),
+			);
+	}
+}
+
+/// Limits how much work [`ChunkLoader`]s do each frame, so loading a large radius at once is spread over several
+/// frames instead of causing a long hitch. Chunks closest to a loader and inside its camera's view are loaded first.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct ChunkLoadBudget {
+	/// chunks spawned per frame, across all loaders
+	pub loads_per_frame: usize,
+	/// chunks despawned per frame, across all loaders
+	pub unloads_per_frame: usize,
+}
+impl Default for ChunkLoadBudget {
+	fn default() -> Self {
+		Self {
+			loads_per_frame: 64,
+			unloads_per_frame: 256,
+		}
+	}
+}
+
+/// Draws chunks far from the camera with less detail, which takes less memory and fewer steps to ray march
+///
+/// Chunks within `full_detail_distance` voxels of the nearest camera are drawn at full detail. Every time the distance
+/// doubles past that, the chunk loses one level of its octree, halving its resolution, down to at most `max_level`
+/// levels. Set `full_detail_distance` to [`f32::INFINITY`] to always draw full detail.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct ChunkLod {
+	pub full_detail_distance: f32,
+	pub max_level: u32,
+}
+impl ChunkLod {
+	/// How many levels to leave off of a chunk `distance` voxels away
+	pub fn level(&self, distance: f32) -> u32 {
+		if distance <= self.full_detail_distance {
+			return 0;
+		}
+		// with no camera the distance is infinite, and a threshold of 0 makes every ratio infinite
+		let ratio = distance / self.full_detail_distance;
+		if !ratio.is_finite() {
+			return self.max_level;
+		}
+		let level = ratio.log2().floor() as u32 + 1;
+		level.min(self.max_level)
+	}
+}
+impl Default for ChunkLod {
+	fn default() -> Self {
+		Self {
+			full_detail_distance: 64.0,
+			max_level: 3,
+		}
 	}
 }
 
 #[derive(Component)]
 pub struct ChunkLoader {
-	radius: i32,
+	shape: LoadShape,
+	hysteresis: i32,
 	loaded: HashMap<IVec3, Arc<Entity>>,
 }
 impl ChunkLoader {
-	pub fn radius(radius: i32) -> Self {
+	pub fn new(shape: LoadShape) -> Self {
 		Self {
-			radius,
+			shape,
+			hysteresis: 1,
 			loaded: HashMap::new(),
 		}
 	}
+
+	/// Loads a cube of chunks, `radius` chunks out from the loader's chunk in every direction
+	pub fn radius(radius: i32) -> Self {
+		Self::new(LoadShape::Cube { radius })
+	}
+
+	/// Chunks are only unloaded once they're more than `hysteresis` chunks outside the load shape, so moving back and
+	/// forth across a chunk border doesn't load and unload the same chunks over and over. Defaults to 1.
+	pub fn with_hysteresis(mut self, hysteresis: i32) -> Self {
+		self.hysteresis = hysteresis;
+		self
+	}
+}
+
+/// The area around a [`ChunkLoader`] that gets loaded. All radii are in chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadShape {
+	Cube {
+		radius: i32,
+	},
+	Sphere {
+		radius: i32,
+	},
+	/// A vertical cylinder, which suits terrain that's much wider than it is tall
+	Cylinder {
+		radius: i32,
+		vertical_radius: i32,
+	},
+}
+impl LoadShape {
+	/// half the size of the box around the shape
+	fn extents(self) -> IVec3 {
+		match self {
+			Self::Cube { radius } | Self::Sphere { radius } => IVec3::splat(radius),
+			Self::Cylinder {
+				radius,
+				vertical_radius,
+			} => IVec3::new(radius, vertical_radius, radius),
+		}
+	}
+
+	/// Whether the chunk at `offset` from the loader's chunk is inside the shape, after growing it by `margin` chunks
+	fn contains(self, offset: IVec3, margin: i32) -> bool {
+		match self {
+			Self::Cube { radius } => offset.abs().max_element() <= radius + margin,
+			Self::Sphere { radius } => offset.length_squared() <= (radius + margin).pow(2),
+			Self::Cylinder {
+				radius,
+				vertical_radius,
+			} => offset.xz().length_squared() <= (radius + margin).pow(2) && offset.y.abs() <= vertical_radius + margin,
+		}
+	}
 }
 
 fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, assets: Res<AssetServer>) {
@@ -48,98 +220,256 @@ fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, assets: Res<A
 		ray_march_shader: assets.load::<Shader>("shaders/ray_march.wgsl"),
 	});
 	commands.insert_resource(LoadedChunks(HashMap::new()));
-	commands.insert_resource(ChunkBox::new(&mut meshes));
+	commands.insert_resource(RegionBox::new(&mut meshes));
 }
 
+#[allow(clippy::too_many_arguments)]
 fn load_chunks(
 	mut commands: Commands,
 	mut loaded_chunks: ResMut<LoadedChunks>,
-	mut materials: ResMut<Assets<ChunkMaterial>>,
-	mut images: ResMut<Assets<Image>>,
-	chunk_box: Res<ChunkBox>,
-	mut loaders: Query<(&mut ChunkLoader, &Transform)>,
+	generator: Res<Generator>,
+	budget: Res<ChunkLoadBudget>,
+	chunk_size: Res<ChunkSize>,
+	mut regions: ResMut<Regions>,
+	mut dirty_lights: ResMut<DirtyLights>,
+	mut loaders: Query<(Entity, &mut ChunkLoader, &Transform, Option<&Frustum>)>,
 ) {
-	for (mut loader, transform) in loaders.iter_mut() {
-		let chunk_position = (transform.translation / 16.0).as_ivec3();
-		let radius3 = IVec3::new(loader.radius, loader.radius, loader.radius);
-		let load_start = chunk_position - radius3;
-		let load_end = chunk_position + radius3;
+	let mut unloads_left = budget.unloads_per_frame;
+	// chunks that still need to be spawned, visible and then nearest first
+	let mut to_load = BinaryHeap::new();
 
-		// unload chunks outside of the radius, if no other loader is loading them
+	for (loader_entity, mut loader, transform, frustum) in loaders.iter_mut() {
+		let loader_position = (transform.translation / **chunk_size as f32).floor().as_ivec3();
+		let extents = loader.shape.extents();
+
+		// unload chunks that have left the shape, if no other loader is loading them. the farthest ones go first.
 		let mut to_remove = vec![];
 		for &chunk_position in loader.loaded.keys() {
-			if chunk_position.x < load_start.x
-				|| chunk_position.x > load_end.x
-				|| chunk_position.y < load_start.y
-				|| chunk_position.y > load_end.y
-				|| chunk_position.z < load_start.z
-				|| chunk_position.z > load_end.z
-			{
+			let offset = chunk_position - loader_position;
+			if !loader.shape.contains(offset, loader.hysteresis) {
 				to_remove.push(chunk_position);
 			}
 		}
+		to_remove.sort_unstable_by_key(|&chunk_position| Reverse((chunk_position - loader_position).length_squared()));
 		for chunk_position in to_remove {
+			// letting go of a chunk another loader still holds doesn't despawn anything, so it's free
+			if Arc::strong_count(&loader.loaded[&chunk_position]) == 1 {
+				if unloads_left == 0 {
+					continue;
+				}
+				unloads_left -= 1;
+			}
+
 			let entity = loader.loaded.remove(&chunk_position).unwrap();
 			if let Some(entity) = Arc::into_inner(entity) {
 				commands.entity(entity).despawn();
 				loaded_chunks.remove(&chunk_position);
+				regions.mark_dirty(chunk_position);
+				dirty_lights.chunk_removed(chunk_position);
 			}
 		}
 
-		// load chunks inside the radius, if not already loaded
-		for x in load_start.x..=load_end.x {
-			for y in load_start.y..=load_end.y {
-				for z in load_start.z..=load_end.z {
-					let chunk_position = IVec3::new(x, y, z);
-					if loader.loaded.contains_key(&chunk_position) {
+		// queue chunks inside the shape, if not already loaded
+		for x in -extents.x..=extents.x {
+			for y in -extents.y..=extents.y {
+				for z in -extents.z..=extents.z {
+					let offset = IVec3::new(x, y, z);
+					let chunk_position = loader_position + offset;
+					if !loader.shape.contains(offset, 0) || loader.loaded.contains_key(&chunk_position) {
 						continue;
 					}
 
-					let chunk_entity = if let Some(entity) = loaded_chunks.get(&chunk_position) {
-						entity.upgrade().unwrap()
-					} else {
-						let entity = commands.spawn((
-							MaterialMeshBundle {
-								mesh: chunk_box.mesh.clone(),
-								transform: Transform::from_xyz(
-									chunk_position.x as f32 * 16.0,
-									chunk_position.y as f32 * 16.0,
-									chunk_position.z as f32 * 16.0,
-								),
-								material: materials.add(ChunkMaterial::new(&mut images)),
-								..default()
-							},
-							Chunk,
-						));
-						let entity = Arc::new(entity.id());
-						loaded_chunks.insert(chunk_position, Arc::downgrade(&entity));
-						entity
-					};
-					loader.loaded.insert(chunk_position, chunk_entity);
+					// sharing a chunk another loader already spawned doesn't cost anything
+					if let Some(chunk_entity) = loaded_chunks.hold(chunk_position) {
+						loader.loaded.insert(chunk_position, chunk_entity);
+						continue;
+					}
+
+					let visible = frustum.is_none_or(|frustum| chunk_in_frustum(frustum, chunk_position, **chunk_size));
+					let distance = offset.length_squared();
+					to_load.push(Reverse((!visible, distance, chunk_position.to_array(), loader_entity)));
 				}
 			}
 		}
 	}
+
+	let mut loads_left = budget.loads_per_frame;
+	while let Some(Reverse((_, _, chunk_position, loader_entity))) = to_load.pop() {
+		let chunk_position = IVec3::from_array(chunk_position);
+
+		// another loader may have spawned this chunk earlier in the queue
+		let chunk_entity = if let Some(chunk_entity) = loaded_chunks.hold(chunk_position) {
+			chunk_entity
+		} else {
+			if loads_left == 0 {
+				break;
+			}
+			loads_left -= 1;
+			spawn_chunk(
+				&mut commands,
+				&mut loaded_chunks,
+				&generator,
+				chunk_position,
+				**chunk_size,
+			)
+		};
+
+		let (_, mut loader, _, _) = loaders.get_mut(loader_entity).unwrap();
+		loader.loaded.insert(chunk_position, chunk_entity);
+	}
+}
+
+fn chunk_in_frustum(frustum: &Frustum, chunk_position: IVec3, chunk_size: u32) -> bool {
+	let min = (chunk_position * chunk_size as i32).as_vec3();
+	let aabb = Aabb::from_min_max(min, min + chunk_size as f32);
+	frustum.intersects_obb(&aabb, &Affine3A::IDENTITY, true, false)
+}
+
+fn spawn_chunk(
+	commands: &mut Commands,
+	loaded_chunks: &mut LoadedChunks,
+	generator: &Generator,
+	chunk_position: IVec3,
+	chunk_size: u32,
+) -> Arc<Entity> {
+	// generate off the main thread. the chunk gets drawn once the task finishes, and despawning the chunk before then
+	// drops the task, which cancels it.
+	let generator = generator.0.clone();
+	let task = AsyncComputeTaskPool::get().spawn(async move {
+		let mut octree = Octree::new(chunk_size);
+		generator.generate(chunk_position, &mut octree);
+		let summary = ChunkSummary::new(&octree);
+		(octree, summary)
+	});
+
+	let entity = commands.spawn((
+		Chunk {
+			position: chunk_position,
+		},
+		ChunkTask(task),
+	));
+	let entity = entity.id();
+	let holders = Arc::new(entity);
+	loaded_chunks.insert(
+		chunk_position,
+		LoadedChunk {
+			entity,
+			holders: Arc::downgrade(&holders),
+			state: ChunkState::Pending,
+		},
+	);
+	holders
+}
+
+/// Attaches the voxel data to chunks whose generation task has finished
+fn finish_chunks(
+	mut commands: Commands,
+	mut loaded_chunks: ResMut<LoadedChunks>,
+	mut chunks: Query<(Entity, &Chunk, &mut ChunkTask)>,
+) {
+	for (entity, chunk, mut task) in chunks.iter_mut() {
+		let Some((octree, summary)) = block_on(poll_once(&mut task.0)) else {
+			continue;
+		};
+
+		if let Some(chunk) = loaded_chunks.get_mut(&chunk.position) {
+			chunk.state = ChunkState::Ready;
+		}
+		commands
+			.entity(entity)
+			.remove::<ChunkTask>()
+			.insert((ChunkData(octree), summary, ChunkLight::default()));
+	}
+}
+
+/// Keeps the summaries of edited chunks up to date. New chunks already have theirs worked out by their generation task.
+fn update_summaries(mut chunks: Query<(Ref<ChunkData>, &mut ChunkSummary)>) {
+	for (data, mut summary) in chunks.iter_mut() {
+		if data.is_changed() && !data.is_added() {
+			*summary = ChunkSummary::new(&data.0);
+		}
+	}
 }
 
 #[derive(Component)]
-struct Chunk;
+struct Chunk {
+	position: IVec3,
+}
 
-#[derive(Deref, DerefMut, Resource)]
-struct LoadedChunks(HashMap<IVec3, Weak<Entity>>);
+#[derive(Component)]
+struct ChunkTask(Task<(Octree, ChunkSummary)>);
 
-#[derive(Resource)]
-struct ChunkBox {
-	mesh: Handle<Mesh>,
+/// The voxels of a chunk that has finished generating. The region it's in is uploaded again whenever this changes.
+#[derive(Component)]
+struct ChunkData(Octree);
+
+/// What's in a chunk, so uploading its region doesn't have to walk the whole octree again
+#[derive(Component)]
+struct ChunkSummary {
+	occupancy: Occupancy,
+	/// every non-empty id in the chunk
+	ids: HashSet<u32>,
 }
-impl ChunkBox {
-	fn new(meshes: &mut Assets<Mesh>) -> Self {
-		let mut cube = Cuboid::new(16., 16., 16.).mesh();
-		cube.translate_by(vec3(8., 8., 8.));
+impl ChunkSummary {
+	fn new(octree: &Octree) -> Self {
+		Self {
+			occupancy: octree.occupancy(),
+			ids: octree.voxel_ids(),
+		}
+	}
+}
+
+#[derive(Deref, Resource)]
+struct Generator(Arc<dyn ChunkGenerator>);
+
+/// Edge length of every chunk, in voxels
+#[derive(Clone, Copy, Deref, Resource)]
+struct ChunkSize(u32);
 
-		let mesh = meshes.add(cube);
-		Self { mesh }
+/// Whether [`VoxelRenderPlugin::ambient_occlusion`] is on
+#[derive(Clone, Copy, Deref, Resource)]
+struct AmbientOcclusion(bool);
+
+/// [`VoxelRenderPlugin::light_illuminance`]
+#[derive(Clone, Copy, Deref, Resource)]
+struct LightIlluminance(f32);
+
+#[derive(Deref, DerefMut, Resource)]
+struct LoadedChunks(HashMap<IVec3, LoadedChunk>);
+impl LoadedChunks {
+	/// The entity of the chunk at `chunk_position`, if it's finished generating
+	fn ready(&self, chunk_position: IVec3) -> Option<Entity> {
+		let chunk = self.get(&chunk_position)?;
+		(chunk.state == ChunkState::Ready).then_some(chunk.entity)
 	}
+
+	/// Shares the chunk at `chunk_position` with another loader, if it's loaded
+	fn hold(&mut self, chunk_position: IVec3) -> Option<Arc<Entity>> {
+		let chunk = self.get_mut(&chunk_position)?;
+		let holders = chunk.holders.upgrade().unwrap_or_else(|| {
+			// a despawned loader let go of this chunk without unloading it, so the new loader adopts it
+			let holders = Arc::new(chunk.entity);
+			chunk.holders = Arc::downgrade(&holders);
+			holders
+		});
+		Some(holders)
+	}
+}
+
+/// A chunk that's been spawned, whether or not it's finished generating
+struct LoadedChunk {
+	entity: Entity,
+	/// shared by every [`ChunkLoader`] holding the chunk. the last one to let go despawns it.
+	holders: Weak<Entity>,
+	state: ChunkState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChunkState {
+	/// generating on another thread, with no [`ChunkData`] yet
+	Pending,
+	/// has its [`ChunkData`], or will once commands are applied
+	Ready,
 }
 
 #[derive(Resource)]
@@ -148,90 +478,104 @@ struct VoxelRenderGlobals {
 	ray_march_shader: Handle<Shader>,
 }
 
-// This is the struct that will be passed to your shader
-#[derive(Asset, AsBindGroup, TypePath, Debug, Clone)]
-struct ChunkMaterial {
-	#[texture(0, dimension = "3d")]
-	chunk: Handle<Image>,
-}
-impl ChunkMaterial {
-	fn new(images: &mut Assets<Image>) -> Self {
-		Self {
-			chunk: init_chunk(images),
-		}
-	}
-}
-impl Material for ChunkMaterial {
-	fn prepass_fragment_shader() -> ShaderRef {
-		"shaders/voxels_prepass.wgsl".into()
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A world with 8³ chunks and none loaded yet
+	pub(crate) fn chunk_world() -> World {
+		let mut world = World::new();
+		world.insert_resource(ChunkSize(8));
+		world.insert_resource(LoadedChunks(HashMap::new()));
+		world
 	}
 
-	fn fragment_shader() -> ShaderRef {
-		"shaders/voxels.wgsl".into()
+	/// Spawns a chunk the way [`finish_chunks`] leaves it, and returns the hold a [`ChunkLoader`] would keep on it
+	pub(crate) fn spawn_ready_chunk(world: &mut World, position: IVec3, octree: Octree) -> Arc<Entity> {
+		let summary = ChunkSummary::new(&octree);
+		let entity = world
+			.spawn((Chunk { position }, ChunkData(octree), summary, ChunkLight::default()))
+			.id();
+		let holders = Arc::new(entity);
+		let chunk = LoadedChunk {
+			entity,
+			holders: Arc::downgrade(&holders),
+			state: ChunkState::Ready,
+		};
+		world.resource_mut::<LoadedChunks>().insert(position, chunk);
+		holders
 	}
 
-	fn alpha_mode(&self) -> AlphaMode {
-		AlphaMode::Blend
+	#[test]
+	fn chunk_lod_level() {
+		let lod = ChunkLod {
+			full_detail_distance: 64.0,
+			max_level: 3,
+		};
+		assert_eq!(lod.level(0.0), 0);
+		assert_eq!(lod.level(64.0), 0);
+		assert_eq!(lod.level(64.5), 1);
+		assert_eq!(lod.level(127.0), 1);
+		assert_eq!(lod.level(128.0), 2);
+		assert_eq!(lod.level(256.0), 3);
+		assert_eq!(lod.level(10_000.0), 3);
+		assert_eq!(lod.level(f32::INFINITY), 3);
 	}
 
-	fn specialize(
-		_pipeline: &MaterialPipeline<Self>,
-		descriptor: &mut RenderPipelineDescriptor,
-		_layout: &MeshVertexBufferLayout,
-		_key: MaterialPipelineKey<Self>,
-	) -> Result<(), SpecializedMeshPipelineError> {
-		descriptor.primitive.cull_mode = None;
+	#[test]
+	fn chunk_lod_level_edge_thresholds() {
+		let zero = ChunkLod {
+			full_detail_distance: 0.0,
+			max_level: 2,
+		};
+		assert_eq!(zero.level(0.0), 0);
+		assert_eq!(zero.level(1.0), 2);
+		assert_eq!(zero.level(f32::INFINITY), 2);
 
-		Ok(())
+		let always_full = ChunkLod {
+			full_detail_distance: f32::INFINITY,
+			max_level: 2,
+		};
+		assert_eq!(always_full.level(1e30), 0);
+		assert_eq!(always_full.level(f32::INFINITY), 0);
 	}
-}
 
-pub fn init_chunk(images: &mut Assets<Image>) -> Handle<Image> {
-	let mut data = vec![0; 16 * 16 * 16 * 4];
+	#[test]
+	fn loaded_chunks_are_only_ready_once_generated() {
+		let mut world = World::new();
+		let entity = world.spawn_empty().id();
+		let holders = Arc::new(entity);
+		let mut loaded_chunks = LoadedChunks(HashMap::new());
+		loaded_chunks.insert(
+			IVec3::ZERO,
+			LoadedChunk {
+				entity,
+				holders: Arc::downgrade(&holders),
+				state: ChunkState::Pending,
+			},
+		);
+		assert_eq!(loaded_chunks.ready(IVec3::ZERO), None);
+
+		loaded_chunks.get_mut(&IVec3::ZERO).unwrap().state = ChunkState::Ready;
+		assert_eq!(loaded_chunks.ready(IVec3::ZERO), Some(entity));
+		assert_eq!(loaded_chunks.ready(IVec3::ONE), None);
+	}
 
-	// set the middle voxel to 1
-	set_voxel(&mut data, 8, 8, 8, 1);
-	// set another voxel to 1
-	set_voxel(&mut data, 10, 8, 8, 1);
-	// set another voxel to 1
-	set_voxel(&mut data, 8, 10, 8, 1);
-	// set another voxel to 1
-	set_voxel(&mut data, 9, 10, 8, 1);
-	// set corner voxel to 1
-	set_voxel(&mut data, 0, 0, 0, 1);
+	#[test]
+	fn orphaned_chunks_are_adopted() {
+		let mut world = chunk_world();
+		let holders = spawn_ready_chunk(&mut world, IVec3::ZERO, Octree::new(8));
+		let entity = *holders;
+		let mut loaded_chunks = world.resource_mut::<LoadedChunks>();
 
-	let size = Extent3d {
-		width: 16,
-		height: 16,
-		depth_or_array_layers: 16,
-	};
-	let format = TextureFormat::Rgba8UnormSrgb;
-	let mut image = Image::new(
-		size,
-		TextureDimension::D3,
-		data,
-		format,
-		RenderAssetUsages::RENDER_WORLD,
-	);
+		let shared = loaded_chunks.hold(IVec3::ZERO).unwrap();
+		assert!(Arc::ptr_eq(&shared, &holders));
+		drop((shared, holders));
 
-	let sampler = ImageSamplerDescriptor {
-		address_mode_u: ImageAddressMode::ClampToEdge,
-		address_mode_v: ImageAddressMode::ClampToEdge,
-		address_mode_w: ImageAddressMode::ClampToEdge,
-		mag_filter: ImageFilterMode::Nearest,
-		min_filter: ImageFilterMode::Nearest,
-		mipmap_filter: ImageFilterMode::Nearest,
-		..Default::default()
-	};
-	image.sampler = ImageSampler::Descriptor(sampler);
-
-	images.add(image)
-}
-
-fn set_voxel(voxels: &mut Vec<u8>, x: usize, y: usize, z: usize, value: u8) {
-	let index = (z * 16 * 16 + y * 16 + x) * 4;
-	voxels[index + 0] = value;
-	voxels[index + 1] = value;
-	voxels[index + 2] = value;
-	voxels[index + 3] = value;
+		// the loaders holding it were despawned without unloading it
+		let adopted = loaded_chunks.hold(IVec3::ZERO).unwrap();
+		assert_eq!(*adopted, entity);
+		assert_eq!(Arc::strong_count(&adopted), 1);
+		assert!(loaded_chunks.hold(IVec3::ONE).is_none());
+	}
 }
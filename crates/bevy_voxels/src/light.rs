@@ -0,0 +1,299 @@
+use crate::{
+	octree::{Occupancy, Octree},
+	palette::{VoxelMaterial, VoxelPalette},
+	region::Regions,
+	Chunk, ChunkData, ChunkSize, ChunkSummary, LoadedChunks,
+};
+use bevy::{prelude::*, utils::HashSet};
+use std::collections::VecDeque;
+
+/// The brightest light a voxel can give off. Light loses one level for every voxel it spreads, so this is also the
+/// farthest it can reach.
+pub const MAX_LIGHT_LEVEL: u8 = 15;
+
+/// The neighbors light can spread to
+const DIRECTIONS: [IVec3; 6] = [IVec3::Y, IVec3::NEG_Y, IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z];
+
+/// How much light reaches each voxel of a chunk, including light that spread in from its neighbors
+///
+/// There are two kinds of light. Light from emissive voxels has a red, green, and blue level, which each spread on
+/// their own so lights of different colors mix. Skylight comes down from open sky at [`MAX_LIGHT_LEVEL`] and keeps it
+/// all the way down through empty voxels, then spreads out from there like any other light, so caves get darker the
+/// farther they are from an opening. Columns with no chunk loaded above them are open to the sky.
+#[derive(Component, Default)]
+pub(crate) struct ChunkLight {
+	/// one value per voxel, ordered by z, then y, then x, with the red, green, and blue levels in the low three bytes
+	/// and the skylight level in the high byte. empty if the whole chunk is dark. voxels that block light get the
+	/// brightest light next to them, so faces can still be lit when the voxel in front of them is in another region.
+	pub(crate) levels: Vec<u32>,
+}
+impl ChunkLight {
+	fn get(&self, idx: usize) -> [u8; 4] {
+		self.levels.get(idx).map_or([0; 4], |level| level.to_le_bytes())
+	}
+}
+
+/// Chunks to light again at the end of the frame, on top of the ones whose voxels changed
+#[derive(Default, Resource)]
+pub(crate) struct DirtyLights(HashSet<IVec3>);
+impl DirtyLights {
+	/// Call when a chunk is removed. Light that spread out of it has to fade from its neighbors, and the chunk below it
+	/// is open to the sky now.
+	pub(crate) fn chunk_removed(&mut self, chunk_position: IVec3) {
+		self.0.extend(DIRECTIONS.map(|direction| chunk_position + direction));
+	}
+}
+
+/// Lights new and edited chunks and the neighbors of removed ones, or every chunk if the palette changed. Light crosses
+/// chunk borders, so whenever a chunk's light changes its neighbors are lit again too, until it stops changing. Each
+/// chunk only needs its own voxels and the edges of its neighbors, so an edit only costs a few chunks' worth of work.
+pub(crate) fn update_lights(
+	palette: Res<VoxelPalette>,
+	chunk_size: Res<ChunkSize>,
+	loaded_chunks: Res<LoadedChunks>,
+	mut regions: ResMut<Regions>,
+	mut dirty_lights: ResMut<DirtyLights>,
+	changed_chunks: Query<&Chunk, Changed<ChunkData>>,
+	mut chunks: Query<(&Chunk, &ChunkData, &ChunkSummary, &mut ChunkLight)>,
+) {
+	let mut dirty: HashSet<_> = if palette.is_changed() {
+		chunks.iter().map(|(chunk, ..)| chunk.position).collect()
+	} else {
+		changed_chunks.iter().map(|chunk| chunk.position).collect()
+	};
+	dirty.extend(dirty_lights.0.drain());
+
+	let chunk_entity = |chunk_position| loaded_chunks.ready(chunk_position);
+	while !dirty.is_empty() {
+		let mut next_dirty = HashSet::new();
+		for chunk_position in dirty {
+			let Some(entity) = chunk_entity(chunk_position) else {
+				continue;
+			};
+			let Ok((_, data, summary, _)) = chunks.get(entity) else {
+				continue;
+			};
+			let neighbor = |direction| {
+				let entity = chunk_entity(chunk_position + direction)?;
+				chunks.get(entity).ok().map(|(_, data, _, light)| (&data.0, light))
+			};
+			let levels = light_chunk(&data.0, summary.occupancy, **chunk_size, &palette, neighbor);
+
+			let (_, _, _, mut light) = chunks.get_mut(entity).unwrap();
+			if light.levels != levels {
+				light.levels = levels;
+				regions.mark_dirty(chunk_position);
+				next_dirty.extend(DIRECTIONS.map(|direction| chunk_position + direction));
+			}
+		}
+		dirty = next_dirty;
+	}
+}
+
+/// Flood fills a chunk with the light from its own emissive voxels and from the edges of its neighbors, and returns the
+/// new levels for [`ChunkLight`]. This starts from scratch each time instead of patching the old levels, so light that
+/// isn't there anymore fades out as its neighbors are lit again.
+fn light_chunk<'a>(
+	octree: &Octree,
+	occupancy: Occupancy,
+	chunk_size: u32,
+	palette: &VoxelPalette,
+	neighbor: impl Fn(IVec3) -> Option<(&'a Octree, &'a ChunkLight)>,
+) -> Vec<u32> {
+	let size = chunk_size as i32;
+	let idx = |pos: IVec3| ((pos.z * size + pos.y) * size + pos.x) as usize;
+	let blocks_light = |id: u32| id != 0 && !palette.get(id).is_translucent();
+
+	let volume = chunk_size.pow(3) as usize;
+	let mut levels = vec![[0u8; 4]; volume];
+	let mut ids = vec![0; volume];
+	let mut queue = VecDeque::new();
+	if occupancy != Occupancy::Empty {
+		for (min, leaf_size, id) in octree.leaves().non_empty() {
+			let material = palette.get(id);
+			let emitted = (material.light > 0).then(|| emitted_light(material));
+			for z in 0..leaf_size {
+				for y in 0..leaf_size {
+					for x in 0..leaf_size {
+						let pos = (min + UVec3::new(x, y, z)).as_ivec3();
+						ids[idx(pos)] = id;
+						if let Some([r, g, b]) = emitted {
+							levels[idx(pos)] = [r, g, b, 0];
+							queue.push_back(pos);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	// the level that reaches the voxel with `to_id` after moving one voxel in `direction`
+	let spread = |level: [u8; 4], direction: IVec3, to_id: u32| {
+		let mut spread = level.map(|level| level.saturating_sub(1));
+		// skylight doesn't fade on its way straight down through empty voxels
+		if direction == IVec3::NEG_Y && level[3] == MAX_LIGHT_LEVEL && to_id == 0 {
+			spread[3] = MAX_LIGHT_LEVEL;
+		}
+		spread
+	};
+	// lights `pos` with light coming from a neighbor at `level`, and returns whether it got any brighter
+	let receive = |levels: &mut [[u8; 4]], pos: IVec3, direction: IVec3, level: [u8; 4]| {
+		let i = idx(pos);
+		let old = levels[i];
+		if blocks_light(ids[i]) {
+			levels[i] = [0, 1, 2, 3].map(|c| old[c].max(level[c]));
+			return false;
+		}
+		let spread = spread(level, direction, ids[i]);
+		levels[i] = [0, 1, 2, 3].map(|c| old[c].max(spread[c]));
+		levels[i] != old
+	};
+
+	for direction in DIRECTIONS {
+		let neighbor = neighbor(direction);
+		let edge = if direction.max_element() > 0 { size - 1 } else { 0 };
+		for a in 0..size {
+			for b in 0..size {
+				// the voxel on this side of the chunk, and the one across the border from it
+				let pos = match direction {
+					IVec3 { x: 0, y: 0, .. } => IVec3::new(a, b, edge),
+					IVec3 { x: 0, .. } => IVec3::new(a, edge, b),
+					_ => IVec3::new(edge, a, b),
+				};
+				let neighbor_pos = (pos + direction).rem_euclid(IVec3::splat(size));
+
+				let level = match neighbor {
+					Some((octree, light)) => {
+						let id = octree.get_voxel(neighbor_pos.as_uvec3());
+						if blocks_light(id) {
+							// only the light a solid voxel gives off itself gets through it, not what it's lit by
+							let [r, g, b] = emitted_light(palette.get(id));
+							[r, g, b, 0]
+						} else {
+							light.get(idx(neighbor_pos))
+						}
+					}
+					None if direction == IVec3::Y => [0, 0, 0, MAX_LIGHT_LEVEL],
+					None => [0; 4],
+				};
+				if level != [0; 4] && receive(&mut levels, pos, -direction, level) {
+					queue.push_back(pos);
+				}
+			}
+		}
+	}
+
+	while let Some(pos) = queue.pop_front() {
+		let level = levels[idx(pos)];
+		for direction in DIRECTIONS {
+			let next = pos + direction;
+			if next.cmplt(IVec3::ZERO).any() || next.cmpge(IVec3::splat(size)).any() {
+				continue;
+			}
+			if receive(&mut levels, next, direction, level) {
+				queue.push_back(next);
+			}
+		}
+	}
+
+	if levels.iter().all(|&level| level == [0; 4]) {
+		return vec![];
+	}
+	levels.into_iter().map(u32::from_le_bytes).collect()
+}
+
+/// The red, green, and blue levels a voxel with this material starts its light at. The brightest channel of `emissive`
+/// gets the full `light` level, and the others are scaled down to match, so the light keeps its color.
+fn emitted_light(material: &VoxelMaterial) -> [u8; 3] {
+	let level = material.light.min(MAX_LIGHT_LEVEL) as f32;
+	let [r, g, b, _] = material.emissive.as_linear_rgba_f32();
+	let brightest = r.max(g).max(b);
+	if brightest <= 0.0 {
+		return [level as u8; 3];
+	}
+	[r, g, b].map(|c| (c / brightest * level).round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{chunk_world, spawn_ready_chunk};
+	use std::sync::Arc;
+
+	const LAMP: u32 = 1;
+
+	fn world() -> World {
+		let mut palette = VoxelPalette::default();
+		palette.set(
+			LAMP,
+			VoxelMaterial {
+				emissive: Color::RED,
+				light: MAX_LIGHT_LEVEL,
+				..default()
+			},
+		);
+
+		let mut world = chunk_world();
+		world.insert_resource(palette);
+		world.insert_resource(Regions::new(1));
+		world.init_resource::<DirtyLights>();
+		world
+	}
+
+	fn light_at(world: &mut World, entity: Entity, pos: UVec3) -> [u8; 4] {
+		let light = world.get::<ChunkLight>(entity).unwrap();
+		light.get(((pos.z * 8 + pos.y) * 8 + pos.x) as usize)
+	}
+
+	#[test]
+	fn light_fades_when_its_chunk_is_removed() {
+		let mut world = world();
+		let mut update_lights = IntoSystem::into_system(update_lights);
+		update_lights.initialize(&mut world);
+
+		let dark = spawn_ready_chunk(&mut world, IVec3::ZERO, Octree::new(8));
+		let mut lamp = Octree::new(8);
+		lamp.set_voxel(UVec3::new(0, 4, 4), LAMP);
+		let lamp = spawn_ready_chunk(&mut world, IVec3::X, lamp);
+		update_lights.run((), &mut world);
+
+		// red light spreads across the border, and both chunks are open to the sky
+		assert_eq!(light_at(&mut world, *lamp, UVec3::new(1, 4, 4)), [14, 0, 0, 15]);
+		assert_eq!(light_at(&mut world, *dark, UVec3::new(7, 4, 4)), [14, 0, 0, 15]);
+		assert_eq!(light_at(&mut world, *dark, UVec3::new(0, 4, 4)), [7, 0, 0, 15]);
+
+		// unload the lamp's chunk the way load_chunks does
+		let lamp = Arc::into_inner(lamp).unwrap();
+		world.despawn(lamp);
+		world.resource_mut::<LoadedChunks>().remove(&IVec3::X);
+		world.resource_mut::<DirtyLights>().chunk_removed(IVec3::X);
+		update_lights.run((), &mut world);
+
+		assert_eq!(light_at(&mut world, *dark, UVec3::new(7, 4, 4)), [0, 0, 0, 15]);
+	}
+
+	#[test]
+	fn skylight_returns_when_the_chunk_above_is_removed() {
+		let mut world = world();
+		let mut update_lights = IntoSystem::into_system(update_lights);
+		update_lights.initialize(&mut world);
+
+		let below = spawn_ready_chunk(&mut world, IVec3::ZERO, Octree::new(8));
+		let mut roof = Octree::new(8);
+		roof.fill_box(UVec3::ZERO, UVec3::new(8, 1, 8), 2);
+		let roof = spawn_ready_chunk(&mut world, IVec3::Y, roof);
+		update_lights.run((), &mut world);
+		assert_eq!(light_at(&mut world, *below, UVec3::new(4, 4, 4)), [0; 4]);
+
+		let roof = Arc::into_inner(roof).unwrap();
+		world.despawn(roof);
+		world.resource_mut::<LoadedChunks>().remove(&IVec3::Y);
+		world.resource_mut::<DirtyLights>().chunk_removed(IVec3::Y);
+		update_lights.run((), &mut world);
+
+		assert_eq!(
+			light_at(&mut world, *below, UVec3::new(4, 4, 4)),
+			[0, 0, 0, MAX_LIGHT_LEVEL]
+		);
+	}
+}
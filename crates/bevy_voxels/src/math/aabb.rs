@@ -1,5 +1,7 @@
 use bevy::math::UVec3;
 
+/// A box of voxel positions from `min` up to but not including `max`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct UAabb {
 	pub min: UVec3,
 	pub max: UVec3,
@@ -10,11 +12,41 @@ impl UAabb {
 	}
 
 	pub fn contains(&self, point: UVec3) -> bool {
-		point.x >= self.min.x
-			&& point.x <= self.max.x
-			&& point.y >= self.min.y
-			&& point.y <= self.max.y
-			&& point.z >= self.min.z
-			&& point.z <= self.max.z
+		point.cmpge(self.min).all() && point.cmplt(self.max).all()
+	}
+
+	/// Whether any position is in both boxes
+	pub fn intersects(&self, other: &UAabb) -> bool {
+		// the overlap has to have some volume, which also rules out boxes that are empty themselves
+		self.min.max(other.min).cmplt(self.max.min(other.max)).all()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn contains_excludes_max() {
+		let aabb = UAabb::new(UVec3::new(2, 4, 6), UVec3::new(4, 8, 12));
+		assert!(aabb.contains(UVec3::new(2, 4, 6)));
+		assert!(aabb.contains(UVec3::new(3, 7, 11)));
+		assert!(!aabb.contains(UVec3::new(4, 7, 11)));
+		assert!(!aabb.contains(UVec3::new(3, 8, 11)));
+		assert!(!aabb.contains(UVec3::new(3, 7, 12)));
+		assert!(!aabb.contains(UVec3::new(1, 4, 6)));
+	}
+
+	#[test]
+	fn intersects() {
+		let aabb = UAabb::new(UVec3::splat(2), UVec3::splat(4));
+		assert!(aabb.intersects(&aabb));
+		assert!(aabb.intersects(&UAabb::new(UVec3::splat(3), UVec3::splat(10))));
+		assert!(aabb.intersects(&UAabb::new(UVec3::ZERO, UVec3::splat(10))));
+		// boxes that only touch don't share any positions
+		assert!(!aabb.intersects(&UAabb::new(UVec3::new(4, 2, 2), UVec3::splat(6))));
+		assert!(!aabb.intersects(&UAabb::new(UVec3::ZERO, UVec3::new(10, 2, 10))));
+		// neither does an empty box
+		assert!(!aabb.intersects(&UAabb::new(UVec3::splat(3), UVec3::new(3, 4, 4))));
 	}
 }
@@ -1,7 +1,23 @@
+mod fill;
+mod leaves;
+mod lod;
+mod occupancy;
+mod raycast;
+mod serialize;
+
 use bevy::{prelude::*, utils::HashMap};
 
 use crate::math::aabb::UAabb;
 
+pub use leaves::Leaves;
+pub use occupancy::Occupancy;
+pub use raycast::RaycastHit;
+pub use serialize::ReadOctreeError;
+
+/// The largest id a voxel can have. Values use their low bit to tell voxels and pointers apart, which leaves 31 bits for
+/// the id.
+pub const MAX_VOXEL_ID: u32 = u32::MAX >> 1;
+
 #[derive(Debug)]
 pub struct Octree {
 	quadrant_size: u32,
@@ -38,11 +54,16 @@ impl Octree {
 
 	/// Each component of `pos` must be less than the octree size
 	///
-	/// `value` must be less than 2^31
+	/// `id` must not be greater than [`MAX_VOXEL_ID`]
 	pub fn set_voxel(&mut self, pos: UVec3, id: u32) {
 		self.voxel_cursor_mut(pos).set_voxel(id);
 	}
 
+	/// Each component of `pos` must be less than the octree size
+	pub fn get_voxel(&self, pos: UVec3) -> u32 {
+		self.voxel_cursor(pos).move_to_leaf().value().voxel_id().unwrap()
+	}
+
 	/// Each component of `pos` must be less than the octree size
 	pub fn voxel_cursor(&self, pos: UVec3) -> VoxelCursor {
 		VoxelCursor::new(self, pos, self.quadrant_size)
@@ -53,30 +74,62 @@ impl Octree {
 		VoxelCursorMut::new(self, pos, self.quadrant_size)
 	}
 
+	pub fn size(&self) -> u32 {
+		self.quadrant_size * 2
+	}
+
+	/// Index of the root node in the node array
+	pub fn entry(&self) -> u32 {
+		self.entry
+	}
+
+	/// Appends the node array to `data`, flattened into the layout `ray_march.wgsl` reads: 8 encoded `OctreeValue`s per
+	/// node, where the value for quadrant `q` is at `node_idx * 8 + q.z * 4 + q.y * 2 + q.x`. Pointers are offset by the
+	/// number of nodes already in `data`, so several octrees can share one buffer. Returns the index of the root node in
+	/// `data`.
+	///
+	/// Nodes in free ranges are included as-is, since nothing points at them.
+	pub fn extend_raw_data(&self, data: &mut Vec<u32>) -> u32 {
+		let base_idx = (data.len() / 8) as u32;
+		data.extend(self.data.iter().flat_map(|node| {
+			node.data
+				.iter()
+				.flatten()
+				.flatten()
+				.map(move |value| match value.pointer_idx() {
+					Some(idx) => OctreeValue::new_pointer(base_idx + idx).0,
+					None => value.0,
+				})
+		}));
+		base_idx + self.entry
+	}
+
 	pub fn get_position(&self) -> IVec3 {
 		self.position
 	}
 
 	/// Set the world position of the octree. This will clear any voxels that are no longer in the octree.
 	pub fn set_position(&mut self, pos: IVec3) {
-		let pos_diff = pos - self.position;
 		let tree_size = self.quadrant_size * 2;
+		// moving by a whole tree or more clears everything
+		let pos_diff = (pos - self.position).clamp(IVec3::splat(-(tree_size as i32)), IVec3::splat(tree_size as i32));
+		self.position = pos;
 
 		// figure out the areas that need to be cleared
 		let (start_x, size_x) = if pos_diff.x > 0 {
 			(0, pos_diff.x as u32)
 		} else {
-			((tree_size as i32 - pos_diff.x) as u32, (-pos_diff.x) as u32)
+			((tree_size as i32 + pos_diff.x) as u32, (-pos_diff.x) as u32)
 		};
 		let (start_y, size_y) = if pos_diff.y > 0 {
 			(0, pos_diff.y as u32)
 		} else {
-			((tree_size as i32 - pos_diff.y) as u32, (-pos_diff.y) as u32)
+			((tree_size as i32 + pos_diff.y) as u32, (-pos_diff.y) as u32)
 		};
 		let (start_z, size_z) = if pos_diff.z > 0 {
 			(0, pos_diff.z as u32)
 		} else {
-			((tree_size as i32 - pos_diff.z) as u32, (-pos_diff.z) as u32)
+			((tree_size as i32 + pos_diff.z) as u32, (-pos_diff.z) as u32)
 		};
 
 		// clear for X movement
@@ -111,65 +164,99 @@ impl Octree {
 	}
 
 	fn clear_area(&mut self, pos: UVec3, size: UVec3) {
-		let area_end = pos + size;
-
-		let mut cursor = self.voxel_cursor_mut(pos);
-		while cursor.pos().x < area_end.x {
-			while cursor.pos().y < area_end.y {
-				while cursor.pos().z < area_end.z {
-					cursor.set_voxel(0);
-					cursor.move_by(IVec3::new(0, 0, 1));
+		self.fill_box(pos, size, 0);
+	}
+
+	/// Rebuilds the node array with only the nodes still in use, in the same order. Freed nodes are normally reused by
+	/// later edits, so this is only worth it once a tree is done being edited, like before saving it or keeping it
+	/// around for a long time.
+	pub fn compact(&mut self) {
+		let mut new_idxs = HashMap::with_capacity(self.map.len());
+		let mut data = Vec::with_capacity(self.map.len());
+		for (data_idx, node) in self.data.iter().enumerate() {
+			// free nodes still hold whatever was in them, which may even match a node that's in use somewhere else
+			if self.map.get(node).is_some_and(|&(idx, _)| idx == data_idx as u32) {
+				new_idxs.insert(data_idx as u32, data.len() as u32);
+				data.push(*node);
+			}
+		}
+
+		let mut map = HashMap::with_capacity(data.len());
+		for (data_idx, node) in data.iter_mut().enumerate() {
+			let (_, refcount) = self.map[node];
+			for value in node.data.iter_mut().flatten().flatten() {
+				if let Some(child_idx) = value.pointer_idx() {
+					*value = OctreeValue::new_pointer(new_idxs[&child_idx]);
 				}
-				cursor.move_by(IVec3::new(0, 1, -(size.z as i32)));
 			}
-			cursor.move_by(IVec3::new(1, -(size.y as i32), 0));
+			map.insert(*node, (data_idx as u32, refcount));
 		}
+
+		self.entry = new_idxs[&self.entry];
+		self.data = data;
+		self.map = map;
+		self.free_ranges.clear();
 	}
 
-	fn set_value(&mut self, data_idx: u32, quadrant: UVec3, value: OctreeValue) -> u32 {
-		// copy the existing node and update the value
-		let mut new_node = self.data[data_idx as usize];
-		new_node.set_value(quadrant, value);
+	/// Returns the index of `node`, adding it if it doesn't exist yet
+	///
+	/// A new node holds a reference to each of its children, but starts out with no references to itself, so it has to be
+	/// referenced by another new node or made the entry before anything else is freed.
+	fn get_or_insert_node(&mut self, node: OctreeNode) -> u32 {
+		if let Some(&(data_idx, _)) = self.map.get(&node) {
+			return data_idx;
+		}
+
+		// reuse a free node if possible, otherwise add a new one
+		let new_data_idx = if !self.free_ranges.is_empty() {
+			let free_range = &mut self.free_ranges[0];
+			let new_data_idx = free_range.0;
 
-		let new_data_idx = self.get_or_insert_node(new_node);
+			self.data[new_data_idx as usize] = node;
 
-		self.free_node(data_idx);
+			free_range.0 += 1;
+			if free_range.0 == free_range.1 {
+				self.free_ranges.remove(0);
+			}
+
+			new_data_idx
+		} else {
+			let new_data_idx = self.data.len() as u32;
+			self.data.push(node);
+			new_data_idx
+		};
+		self.map.insert(node, (new_data_idx, 0));
+
+		for child_idx in node.child_idxs() {
+			self.ref_node(child_idx);
+		}
 
 		new_data_idx
 	}
 
-	fn get_or_insert_node(&mut self, node: OctreeNode) -> u32 {
-		// reuse another node if possible, otherwise add a new one
-		let (new_data_idx, refcount) = self.map.entry(node).or_insert_with(|| {
-			if self.free_ranges.len() > 0 {
-				// take free node
-				let free_range = &mut self.free_ranges[0];
-				let new_data_idx = free_range.0;
-
-				self.data[new_data_idx as usize] = node;
-
-				free_range.0 += 1;
-				if free_range.0 == free_range.1 {
-					self.free_ranges.remove(0);
-				}
+	fn ref_node(&mut self, data_idx: u32) {
+		self.map.get_mut(&self.data[data_idx as usize]).unwrap().1 += 1;
+	}
 
-				(new_data_idx, 0)
-			} else {
-				// add new node
-				let new_data_idx = self.data.len() as u32;
-				self.data.push(node);
-				(new_data_idx, 0)
-			}
-		});
-		*refcount += 1;
-		*new_data_idx
+	/// Makes `data_idx` the root node, and frees whatever is no longer reachable from it
+	fn set_entry(&mut self, data_idx: u32) {
+		self.ref_node(data_idx);
+		self.free_node(self.entry);
+		self.entry = data_idx;
 	}
 
+	/// Drops a reference to a node. Once nothing references it, it's freed and drops its references to its children.
 	fn free_node(&mut self, data_idx: u32) {
-		let (_, refcount) = self.map.get_mut(&self.data[data_idx as usize]).unwrap();
+		let node = self.data[data_idx as usize];
+		let (_, refcount) = self.map.get_mut(&node).unwrap();
 		*refcount -= 1;
 
 		if *refcount == 0 {
+			self.map.remove(&node);
+			for child_idx in node.child_idxs() {
+				self.free_node(child_idx);
+			}
+
 			// this will always fail, because start_idx must already be free, but it'll tell us where to insert this node
 			let search_idx = self
 				.free_ranges
@@ -193,7 +280,8 @@ impl Octree {
 
 					return;
 				}
-			} else if search_idx < self.free_ranges.len() {
+			}
+			if search_idx < self.free_ranges.len() {
 				let (next_start_idx, _) = self.free_ranges[search_idx];
 				if next_start_idx == data_idx + 1 {
 					// adjust next range
@@ -204,9 +292,6 @@ impl Octree {
 
 			// insert new range
 			self.free_ranges.insert(search_idx, (data_idx, data_idx + 1));
-
-			// remove from map
-			self.map.remove(&self.data[data_idx as usize]);
 		}
 	}
 }
@@ -237,16 +322,31 @@ impl OctreeNode {
 	fn set_value(&mut self, quadrant: UVec3, value: OctreeValue) {
 		self.data[quadrant.z as usize][quadrant.y as usize][quadrant.x as usize] = value;
 	}
+
+	/// The leaf every quadrant holds, if they're all the same leaf
+	fn uniform_leaf(&self) -> Option<OctreeValue> {
+		let first = self.data[0][0][0];
+		let uniform = first.is_voxel() && self.data.iter().flatten().flatten().all(|&value| value == first);
+		uniform.then_some(first)
+	}
+
+	fn child_idxs(&self) -> impl Iterator<Item = u32> + '_ {
+		self.data
+			.iter()
+			.flatten()
+			.flatten()
+			.filter_map(|value| value.pointer_idx())
+	}
 }
 
 #[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
-pub struct OctreeValue(u32);
+pub struct OctreeValue(pub(crate) u32);
 impl OctreeValue {
-	fn new_leaf(value: u32) -> Self {
+	pub(crate) fn new_leaf(value: u32) -> Self {
 		Self(value << 1)
 	}
 
-	fn new_pointer(value: u32) -> Self {
+	pub(crate) fn new_pointer(value: u32) -> Self {
 		Self((value << 1) | 1)
 	}
 
@@ -339,34 +439,50 @@ impl<'a> VoxelCursorMut<'a> {
 		self.inner.value(&self.octree.data)
 	}
 
+	/// `id` must not be greater than [`MAX_VOXEL_ID`]
 	pub fn set_voxel(&mut self, id: u32) {
+		// the top bit would be shifted out, turning the voxel into a different one
+		assert!(id <= MAX_VOXEL_ID, "voxel id {id} is greater than MAX_VOXEL_ID");
 		self.move_to_leaf();
 
-		if id == self.value().to_u32() {
+		let old_value = self.value();
+		let mut value = OctreeValue::new_leaf(id);
+		if value == old_value {
 			return;
 		}
 
-		while self.quadrant_size() > 1 {
-			self.split_quadrant();
-		}
-
-		self.set_value(OctreeValue::new_leaf(id));
+		// nodes are never modified in place, since they might be shared. instead, build new nodes from the bottom up,
+		// starting with the ones that split the old leaf down to a single voxel
+		let mut quadrant_size = 1;
+		while quadrant_size < self.quadrant_size() {
+			let mut node = OctreeNode::with_value(old_value);
+			node.set_value(self.inner.quadrant_at(quadrant_size), value);
 
-		let mut child_idx = self.inner.data_idx;
-		let mut parent_quadrant_size = self.quadrant_size() * 2;
-		for parent_idx in self.inner.parent_idxs.iter_mut().rev() {
-			let node_pos = self.inner.pos & (parent_quadrant_size * 2 - 1);
-			let quadrant = get_quadrant(node_pos, parent_quadrant_size);
-
-			*parent_idx = self
-				.octree
-				.set_value(*parent_idx, quadrant, OctreeValue::new_pointer(child_idx));
-			child_idx = *parent_idx;
-			parent_quadrant_size *= 2;
+			let data_idx = self.octree.get_or_insert_node(node);
+			value = OctreeValue::new_pointer(data_idx);
+			quadrant_size *= 2;
 		}
-		if self.inner.parent_idxs.len() > 0 {
-			self.octree.entry = self.inner.parent_idxs[0];
+
+		// then copy every node on the path back up to the root, pointing each one at the new copy of its child. nodes
+		// that end up as eight of the same leaf become that leaf instead, so the tree never has more nodes than it needs.
+		// the root always stays a node.
+		let root_idx = self.inner.parent_idxs.first().copied().unwrap_or(self.inner.data_idx);
+		for &data_idx in std::iter::once(&self.inner.data_idx).chain(self.inner.parent_idxs.iter().rev()) {
+			let mut node = self.octree.data[data_idx as usize];
+			node.set_value(self.inner.quadrant_at(quadrant_size), value);
+			quadrant_size *= 2;
+
+			value = match node.uniform_leaf() {
+				Some(leaf) if data_idx != root_idx => leaf,
+				_ => OctreeValue::new_pointer(self.octree.get_or_insert_node(node)),
+			};
 		}
+		self.octree.set_entry(value.pointer_idx().unwrap());
+
+		// leave the cursor on the new voxel, which may have been merged into a bigger leaf
+		let root_quadrant_size = self.octree.quadrant_size;
+		self.inner = VoxelCursorInner::new(self.octree.entry, self.inner.pos, root_quadrant_size);
+		self.move_to_leaf();
 	}
 
 	pub fn move_to_leaf(&mut self) {
@@ -384,32 +500,6 @@ impl<'a> VoxelCursorMut<'a> {
 	pub fn quadrant_size(&self) -> u32 {
 		self.inner.quadrant_size
 	}
-
-	/// x, y, and z must be 0 or 1
-	fn split_quadrant(&mut self) {
-		if self.inner.quadrant_size == 1 {
-			panic!("Can't split a quadrant of size 1");
-		}
-		if !self.is_leaf() {
-			panic!("Quadrant already split");
-		}
-
-		let new_node = OctreeNode::with_value(self.value());
-		let new_data_idx = self.octree.get_or_insert_node(new_node);
-
-		self.set_value(OctreeValue::new_pointer(new_data_idx));
-		if self.inner.parent_idxs.len() == 0 {
-			self.octree.entry = self.inner.data_idx;
-		}
-
-		self.inner.move_to_child_idx(new_data_idx);
-	}
-
-	fn set_value(&mut self, value: OctreeValue) {
-		self.inner.data_idx = self
-			.octree
-			.set_value(self.inner.data_idx, self.inner.get_quadrant(), value);
-	}
 }
 
 #[derive(Debug)]
@@ -433,14 +523,14 @@ impl VoxelCursorInner {
 	}
 
 	fn move_by(&mut self, pos: IVec3) {
-		let subtree_box = self.subtree_box();
-
 		// TODO: replace with wrapping_add_signed once glam is updated
-		self.pos = (self.pos.as_ivec3() + pos).as_uvec3();
+		let new_pos = (self.pos.as_ivec3() + pos).as_uvec3();
 
-		while !subtree_box.contains(self.pos) {
+		// climb until the node we're in covers the new position too
+		while !self.subtree_box().contains(new_pos) {
 			self.move_to_parent();
 		}
+		self.pos = new_pos;
 	}
 
 	fn is_leaf(&self, data: &[OctreeNode]) -> bool {
@@ -463,10 +553,15 @@ impl VoxelCursorInner {
 	}
 
 	fn get_quadrant(&self) -> UVec3 {
+		self.quadrant_at(self.quadrant_size)
+	}
+
+	/// The quadrant `pos` is in, within the node of size `quadrant_size * 2` that contains it
+	fn quadrant_at(&self, quadrant_size: u32) -> UVec3 {
 		// similar to `self.pos %= self.quadrant_size` but hopefully faster. need to benchmark
 		// this works as long as quadrant_size is a power of 2
-		let node_pos = self.pos & (self.quadrant_size * 2 - 1);
-		get_quadrant(node_pos, self.quadrant_size)
+		let node_pos = self.pos & (quadrant_size * 2 - 1);
+		get_quadrant(node_pos, quadrant_size)
 	}
 
 	fn move_to_child_idx(&mut self, idx: u32) {
@@ -493,3 +588,263 @@ fn get_quadrant(pos: UVec3, quadrant_size: u32) -> UVec3 {
 	// this works as long as each component of pos is less than quadrant_size * 2
 	pos >> quadrant_size.trailing_zeros()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bevy::utils::HashSet;
+
+	/// A small xorshift generator, so the tests are the same every run
+	pub(super) struct Rng(pub(super) u64);
+	impl Rng {
+		pub(super) fn next(&mut self, max: u32) -> u32 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			((self.0 >> 16) % max as u64) as u32
+		}
+
+		pub(super) fn pos(&mut self, size: u32) -> UVec3 {
+			UVec3::new(self.next(size), self.next(size), self.next(size))
+		}
+	}
+
+	/// Checks that the map holds exactly the nodes reachable from the entry, with one reference for every pointer to
+	/// them plus one for the entry, and that every other node is in a free range
+	pub(super) fn assert_consistent(octree: &Octree) {
+		let mut refcounts = HashMap::new();
+		refcounts.insert(octree.entry, 1);
+		let mut visited = HashSet::new();
+		let mut stack = vec![octree.entry];
+		while let Some(data_idx) = stack.pop() {
+			if !visited.insert(data_idx) {
+				continue;
+			}
+			for child_idx in octree.data[data_idx as usize].child_idxs() {
+				*refcounts.entry(child_idx).or_insert(0) += 1;
+				stack.push(child_idx);
+			}
+		}
+
+		assert_eq!(octree.map.len(), refcounts.len(), "map holds unreachable nodes");
+		for (&data_idx, &refcount) in &refcounts {
+			let node = &octree.data[data_idx as usize];
+			assert_eq!(octree.map.get(node), Some(&(data_idx, refcount)), "node {data_idx}");
+			if data_idx != octree.entry {
+				assert_eq!(node.uniform_leaf(), None, "node {data_idx} should have been merged");
+			}
+		}
+
+		let free: Vec<_> = octree.free_ranges.iter().flat_map(|&(start, end)| start..end).collect();
+		assert!(
+			free.windows(2).all(|pair| pair[0] < pair[1]),
+			"free ranges overlap or are out of order"
+		);
+		assert!(
+			octree.free_ranges.windows(2).all(|pair| pair[0].1 < pair[1].0),
+			"free ranges should have been merged"
+		);
+		assert_eq!(free.len() + refcounts.len(), octree.data.len());
+		assert!(free.iter().all(|idx| !refcounts.contains_key(idx)));
+	}
+
+	#[test]
+	fn set_get_round_trip() {
+		let mut rng = Rng(1);
+		let size = 16;
+		let mut octree = Octree::new(size);
+		let mut voxels = vec![0; size.pow(3) as usize];
+		for _ in 0..4000 {
+			let pos = rng.pos(size);
+			let id = rng.next(4);
+			octree.set_voxel(pos, id);
+			voxels[((pos.z * size + pos.y) * size + pos.x) as usize] = id;
+		}
+		assert_consistent(&octree);
+
+		for z in 0..size {
+			for y in 0..size {
+				for x in 0..size {
+					let id = voxels[((z * size + y) * size + x) as usize];
+					assert_eq!(octree.get_voxel(UVec3::new(x, y, z)), id);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn set_voxel_leaves_the_cursor_on_it() {
+		let mut octree = Octree::new(8);
+		let mut cursor = octree.voxel_cursor_mut(UVec3::new(5, 2, 7));
+		cursor.set_voxel(3);
+		assert_eq!(cursor.value().voxel_id(), Some(3));
+		assert_eq!(cursor.quadrant_size(), 1);
+		assert_eq!(cursor.pos(), UVec3::new(5, 2, 7));
+	}
+
+	#[test]
+	fn overwriting_shared_subtrees() {
+		let mut octree = Octree::new(8);
+		// the same pattern in two quadrants of the root, so they share one subtree
+		for offset in [UVec3::ZERO, UVec3::new(4, 0, 0)] {
+			octree.set_voxel(offset + UVec3::new(1, 2, 3), 5);
+			octree.set_voxel(offset + UVec3::new(0, 0, 0), 6);
+		}
+		let root = octree.data[octree.entry as usize];
+		let shared = root.value(UVec3::ZERO).pointer_idx().unwrap();
+		assert_eq!(root.value(UVec3::X).pointer_idx(), Some(shared));
+		assert_eq!(octree.map[&octree.data[shared as usize]], (shared, 2));
+		assert_consistent(&octree);
+
+		// editing one copy must leave the other alone
+		octree.set_voxel(UVec3::new(1, 2, 3), 7);
+		assert_eq!(octree.get_voxel(UVec3::new(1, 2, 3)), 7);
+		assert_eq!(octree.get_voxel(UVec3::new(5, 2, 3)), 5);
+		assert_eq!(octree.map[&octree.data[shared as usize]], (shared, 1));
+		assert_consistent(&octree);
+
+		// and once they match again, they share a subtree again
+		octree.set_voxel(UVec3::new(1, 2, 3), 5);
+		let root = octree.data[octree.entry as usize];
+		assert_eq!(root.value(UVec3::ZERO), root.value(UVec3::X));
+		assert_consistent(&octree);
+
+		// clearing both frees everything but the root
+		for offset in [UVec3::ZERO, UVec3::new(4, 0, 0)] {
+			octree.set_voxel(offset + UVec3::new(1, 2, 3), 0);
+			octree.set_voxel(offset, 0);
+		}
+		assert_eq!(octree.map.len(), 1);
+		assert_consistent(&octree);
+	}
+
+	#[test]
+	fn free_nodes_are_reused() {
+		let mut octree = Octree::new(16);
+		for x in 0..16 {
+			octree.set_voxel(UVec3::new(x, x, x), 1);
+		}
+		let len = octree.data.len();
+		for x in 0..16 {
+			octree.set_voxel(UVec3::new(x, x, x), 0);
+		}
+		assert_consistent(&octree);
+		assert_eq!(octree.data.len(), len);
+		assert_eq!(
+			octree.free_ranges.iter().map(|(start, end)| end - start).sum::<u32>(),
+			len as u32 - 1
+		);
+
+		// the same edits again fit in the nodes that were freed
+		for x in 0..16 {
+			octree.set_voxel(UVec3::new(x, x, x), 2);
+		}
+		assert_consistent(&octree);
+		assert_eq!(octree.data.len(), len);
+	}
+
+	/// Fills an octree, moves it by `diff`, and checks that exactly the voxels `cleared` picks were cleared
+	fn assert_move_clears(diff: IVec3, cleared: impl Fn(UVec3) -> bool) {
+		let mut octree = Octree::new(8);
+		octree.set_position(IVec3::splat(16));
+		octree.fill_box(UVec3::ZERO, UVec3::splat(8), 1);
+		octree.set_position(IVec3::splat(16) + diff);
+		assert_eq!(octree.get_position(), IVec3::splat(16) + diff);
+		assert_consistent(&octree);
+
+		for z in 0..8 {
+			for y in 0..8 {
+				for x in 0..8 {
+					let pos = UVec3::new(x, y, z);
+					let id = if cleared(pos) { 0 } else { 1 };
+					assert_eq!(octree.get_voxel(pos), id, "moving by {diff}, at {pos}");
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn set_position_clears_what_left_the_tree() {
+		assert_move_clears(IVec3::ZERO, |_| false);
+		assert_move_clears(IVec3::new(3, 0, 0), |pos| pos.x < 3);
+		assert_move_clears(IVec3::new(-3, 0, 0), |pos| pos.x >= 5);
+		assert_move_clears(IVec3::new(0, 1, 0), |pos| pos.y < 1);
+		assert_move_clears(IVec3::new(0, 0, -7), |pos| pos.z >= 1);
+		assert_move_clears(IVec3::new(2, -1, 4), |pos| pos.x < 2 || pos.y >= 7 || pos.z < 4);
+		assert_move_clears(IVec3::new(-5, 6, -2), |pos| pos.x >= 3 || pos.y < 6 || pos.z >= 6);
+		// a whole tree or more clears everything
+		assert_move_clears(IVec3::new(8, 0, 0), |_| true);
+		assert_move_clears(IVec3::new(0, -100, 0), |_| true);
+		assert_move_clears(IVec3::new(1000, 1000, -1000), |_| true);
+	}
+
+	#[test]
+	fn set_position_is_relative_to_the_last_position() {
+		let mut octree = Octree::new(8);
+		octree.set_position(IVec3::new(4, 0, 0));
+		octree.fill_box(UVec3::ZERO, UVec3::splat(8), 1);
+		// moving to where it already is doesn't clear anything
+		octree.set_position(IVec3::new(4, 0, 0));
+		assert!(octree.leaves().all(|(_, _, id)| id == 1));
+	}
+
+	#[test]
+	fn move_by_across_nodes() {
+		let mut rng = Rng(9);
+		let mut octree = Octree::new(16);
+		for _ in 0..500 {
+			octree.set_voxel(rng.pos(16), rng.next(4) + 1);
+		}
+
+		let mut cursor = octree.voxel_cursor(UVec3::new(3, 3, 3));
+		cursor.move_to_leaf();
+		for _ in 0..1000 {
+			let target = rng.pos(16);
+			cursor.move_by(target.as_ivec3() - cursor.pos().as_ivec3());
+			assert_eq!(cursor.pos(), target);
+			cursor.move_to_leaf();
+			assert_eq!(cursor.value().voxel_id(), Some(octree.get_voxel(target)), "at {target}");
+		}
+
+		// one voxel over, into the next node on every level
+		let mut cursor = octree.voxel_cursor(UVec3::splat(7));
+		cursor.move_to_leaf();
+		cursor.move_by(IVec3::ONE);
+		cursor.move_to_leaf();
+		assert_eq!(cursor.value().voxel_id(), Some(octree.get_voxel(UVec3::splat(8))));
+	}
+
+	#[test]
+	fn compact() {
+		let mut rng = Rng(13);
+		let mut octree = Octree::new(16);
+		for _ in 0..3000 {
+			octree.set_voxel(rng.pos(16), rng.next(3));
+		}
+		octree.fill_box(UVec3::ZERO, UVec3::new(16, 8, 16), 0);
+		assert!(!octree.free_ranges.is_empty());
+		let leaves: Vec<_> = octree.leaves().collect();
+
+		octree.compact();
+		assert_consistent(&octree);
+		assert!(octree.free_ranges.is_empty());
+		assert_eq!(octree.data.len(), octree.map.len());
+		assert_eq!(octree.leaves().collect::<Vec<_>>(), leaves);
+
+		// edits after compacting still keep the refcounts right
+		for _ in 0..500 {
+			octree.set_voxel(rng.pos(16), rng.next(3));
+		}
+		assert_consistent(&octree);
+	}
+
+	#[test]
+	fn random_edits_stay_consistent() {
+		let mut rng = Rng(7);
+		let mut octree = Octree::new(8);
+		for _ in 0..2000 {
+			octree.set_voxel(rng.pos(8), rng.next(3));
+			assert_consistent(&octree);
+		}
+	}
+}
@@ -0,0 +1,195 @@
+use super::{Octree, OctreeNode, OctreeValue, MAX_VOXEL_ID};
+use bevy::prelude::*;
+
+impl Octree {
+	/// Sets every voxel in `min..min + size` to `id`. Quadrants that are entirely inside the box become a single leaf,
+	/// and nodes whose children all end up as the same leaf are merged back into one, so the cost depends on the surface
+	/// of the box rather than its volume.
+	///
+	/// `min + size` must not be greater than the octree size on any axis, and `id` must not be greater than
+	/// [`MAX_VOXEL_ID`]
+	pub fn fill_box(&mut self, min: UVec3, size: UVec3, id: u32) {
+		assert!(id <= MAX_VOXEL_ID, "voxel id {id} is greater than MAX_VOXEL_ID");
+		// saturating, so a box that would overflow fails the bounds check instead of wrapping around
+		let max = min.saturating_add(size);
+		assert!(
+			max.cmple(UVec3::splat(self.size())).all(),
+			"box at {min} with size {size} doesn't fit in an octree of size {}",
+			self.size()
+		);
+		if size.cmpeq(UVec3::ZERO).any() {
+			return;
+		}
+
+		let fill = Fill {
+			min,
+			max,
+			leaf: OctreeValue::new_leaf(id),
+		};
+		let root = self.fill_value(OctreeValue::new_pointer(self.entry), UVec3::ZERO, self.size(), &fill);
+		// the root is always a node, even if the whole tree is one id
+		let root_idx = match root.pointer_idx() {
+			Some(root_idx) => root_idx,
+			None => self.get_or_insert_node(OctreeNode::with_value(root)),
+		};
+		self.set_entry(root_idx);
+	}
+
+	/// Returns what `value` becomes after filling, where `value` covers the cube of `quadrant_size` at `quadrant_min`.
+	/// Like `VoxelCursorMut::set_voxel`, nodes are never modified in place, since they might be shared.
+	fn fill_value(&mut self, value: OctreeValue, quadrant_min: UVec3, quadrant_size: u32, fill: &Fill) -> OctreeValue {
+		let quadrant_max = quadrant_min + quadrant_size;
+		if quadrant_max.cmple(fill.min).any() || quadrant_min.cmpge(fill.max).any() {
+			return value;
+		}
+		if quadrant_min.cmpge(fill.min).all() && quadrant_max.cmple(fill.max).all() {
+			return fill.leaf;
+		}
+		if value == fill.leaf {
+			return value;
+		}
+
+		// the box only covers part of this quadrant, so fill its children, splitting it first if it's a leaf
+		let node = match value.pointer_idx() {
+			Some(data_idx) => self.data[data_idx as usize],
+			None => OctreeNode::with_value(value),
+		};
+		let child_size = quadrant_size / 2;
+		let mut new_node = node;
+		for z in 0..2 {
+			for y in 0..2 {
+				for x in 0..2 {
+					let quadrant = UVec3::new(x, y, z);
+					let child = self.fill_value(
+						node.value(quadrant),
+						quadrant_min + quadrant * child_size,
+						child_size,
+						fill,
+					);
+					new_node.set_value(quadrant, child);
+				}
+			}
+		}
+
+		if value.is_pointer() && new_node == node {
+			return value;
+		}
+		match new_node.uniform_leaf() {
+			Some(leaf) => leaf,
+			None => OctreeValue::new_pointer(self.get_or_insert_node(new_node)),
+		}
+	}
+}
+
+struct Fill {
+	min: UVec3,
+	max: UVec3,
+	leaf: OctreeValue,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::{assert_consistent, Rng};
+	use super::*;
+
+	/// Fills the box one voxel at a time, which is slow but easy to get right
+	fn fill_each(octree: &mut Octree, min: UVec3, size: UVec3, id: u32) {
+		for z in min.z..min.z + size.z {
+			for y in min.y..min.y + size.y {
+				for x in min.x..min.x + size.x {
+					octree.set_voxel(UVec3::new(x, y, z), id);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn matches_set_voxel() {
+		let mut rng = Rng(3);
+		let size = 16;
+		for _ in 0..200 {
+			let mut filled = Octree::new(size);
+			let mut expected = Octree::new(size);
+			for _ in 0..4 {
+				let min = rng.pos(size);
+				let box_size = UVec3::new(
+					rng.next(size - min.x + 1),
+					rng.next(size - min.y + 1),
+					rng.next(size - min.z + 1),
+				);
+				let id = rng.next(3);
+				filled.fill_box(min, box_size, id);
+				fill_each(&mut expected, min, box_size, id);
+				assert_consistent(&filled);
+			}
+
+			// merging uniform nodes means there's only one way to store the same voxels, so the trees match exactly
+			assert_eq!(filled.map.len(), expected.map.len());
+			assert_eq!(
+				filled.leaves().collect::<Vec<_>>(),
+				expected.leaves().collect::<Vec<_>>()
+			);
+		}
+	}
+
+	#[test]
+	fn partial_quadrants() {
+		let mut octree = Octree::new(8);
+		// covers all of quadrant (0, 0, 0) and part of the quadrants next to it
+		octree.fill_box(UVec3::ZERO, UVec3::new(5, 4, 4), 1);
+		assert_consistent(&octree);
+
+		let root = octree.data[octree.entry as usize];
+		assert_eq!(root.value(UVec3::ZERO), OctreeValue::new_leaf(1));
+		assert!(root.value(UVec3::X).is_pointer());
+		assert_eq!(root.value(UVec3::Y), OctreeValue::new_leaf(0));
+		assert_eq!(octree.get_voxel(UVec3::new(4, 3, 3)), 1);
+		assert_eq!(octree.get_voxel(UVec3::new(5, 0, 0)), 0);
+		assert_eq!(
+			octree.leaves().non_empty().map(|(_, size, _)| size.pow(3)).sum::<u32>(),
+			80
+		);
+	}
+
+	#[test]
+	fn merges_uniform_nodes() {
+		let mut octree = Octree::new(8);
+		octree.fill_box(UVec3::ZERO, UVec3::new(3, 4, 4), 2);
+		assert!(octree.map.len() > 1);
+		// finishing the quadrant turns it back into a single leaf
+		octree.fill_box(UVec3::new(3, 0, 0), UVec3::new(1, 4, 4), 2);
+		assert_consistent(&octree);
+		assert_eq!(octree.map.len(), 1);
+		assert_eq!(octree.leaves().non_empty().collect::<Vec<_>>(), [(UVec3::ZERO, 4, 2)]);
+
+		// and filling everything leaves just the root, which always stays a node
+		octree.fill_box(UVec3::ZERO, UVec3::splat(8), 5);
+		assert_consistent(&octree);
+		assert_eq!(octree.map.len(), 1);
+		assert_eq!(
+			octree.data[octree.entry as usize],
+			OctreeNode::with_value(OctreeValue::new_leaf(5))
+		);
+	}
+
+	#[test]
+	fn empty_box() {
+		let mut octree = Octree::new(8);
+		octree.set_voxel(UVec3::ONE, 1);
+		octree.fill_box(UVec3::ZERO, UVec3::new(8, 0, 8), 2);
+		octree.fill_box(UVec3::splat(8), UVec3::ZERO, 2);
+		assert_eq!(octree.leaves().non_empty().collect::<Vec<_>>(), [(UVec3::ONE, 1, 1)]);
+	}
+
+	#[test]
+	#[should_panic(expected = "doesn't fit")]
+	fn out_of_bounds() {
+		Octree::new(8).fill_box(UVec3::new(4, 0, 0), UVec3::new(5, 1, 1), 1);
+	}
+
+	#[test]
+	#[should_panic(expected = "doesn't fit")]
+	fn overflowing_box() {
+		Octree::new(8).fill_box(UVec3::new(4, 0, 0), UVec3::new(u32::MAX, 1, 1), 1);
+	}
+}
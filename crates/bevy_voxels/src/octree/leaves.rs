@@ -0,0 +1,173 @@
+use super::{Octree, OctreeValue};
+use crate::math::aabb::UAabb;
+use bevy::prelude::*;
+
+impl Octree {
+	/// Walks every leaf in the tree, which is the only way to see what's in it without checking every position. Each
+	/// leaf is a cube of voxels with the same id. Nodes used in more than one place are visited once per place.
+	pub fn leaves(&self) -> Leaves<'_> {
+		Leaves {
+			octree: self,
+			stack: vec![(OctreeValue::new_pointer(self.entry), UVec3::ZERO, self.size())],
+			non_empty: false,
+			bounds: None,
+		}
+	}
+}
+
+/// An iterator over the leaves of an [`Octree`], returned by [`Octree::leaves`]. Yields `(min, size, id)` for each leaf,
+/// which covers `min..min + size` on every axis, ordered by z, then y, then x within each node.
+#[derive(Clone, Debug)]
+pub struct Leaves<'a> {
+	octree: &'a Octree,
+	/// (value, min, size) of the quadrants left to visit, with the next one on top
+	stack: Vec<(OctreeValue, UVec3, u32)>,
+	non_empty: bool,
+	bounds: Option<UAabb>,
+}
+impl Leaves<'_> {
+	/// Skips leaves with id 0
+	pub fn non_empty(mut self) -> Self {
+		self.non_empty = true;
+		self
+	}
+
+	/// Only yields leaves that overlap `bounds`, and doesn't visit any part of the tree outside it. Leaves are yielded
+	/// whole, so they can stick out of `bounds`.
+	pub fn within(mut self, bounds: UAabb) -> Self {
+		self.bounds = Some(bounds);
+		self
+	}
+}
+impl Iterator for Leaves<'_> {
+	type Item = (UVec3, u32, u32);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some((value, min, size)) = self.stack.pop() {
+			if let Some(bounds) = &self.bounds {
+				if !bounds.intersects(&UAabb::new(min, min + size)) {
+					continue;
+				}
+			}
+
+			let Some(data_idx) = value.pointer_idx() else {
+				if self.non_empty && value.to_u32() == 0 {
+					continue;
+				}
+				return Some((min, size, value.to_u32()));
+			};
+
+			// pushed in reverse, so they come off the stack in order
+			let node = &self.octree.data[data_idx as usize];
+			let child_size = size / 2;
+			for z in (0..2).rev() {
+				for y in (0..2).rev() {
+					for x in (0..2).rev() {
+						let quadrant = UVec3::new(x, y, z);
+						self.stack
+							.push((node.value(quadrant), min + quadrant * child_size, child_size));
+					}
+				}
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::Rng;
+	use super::*;
+
+	fn octree() -> Octree {
+		let mut rng = Rng(11);
+		let mut octree = Octree::new(16);
+		octree.fill_box(UVec3::new(0, 0, 0), UVec3::new(16, 5, 16), 1);
+		octree.fill_box(UVec3::new(3, 5, 6), UVec3::new(7, 4, 2), 2);
+		for _ in 0..200 {
+			octree.set_voxel(rng.pos(16), rng.next(4));
+		}
+		octree
+	}
+
+	#[test]
+	fn cover_every_voxel_once() {
+		let octree = octree();
+		let mut seen = vec![false; 16 * 16 * 16];
+		for (min, size, id) in octree.leaves() {
+			assert!(size.is_power_of_two());
+			assert_eq!(min % size, UVec3::ZERO, "leaves are aligned to their size");
+			for z in min.z..min.z + size {
+				for y in min.y..min.y + size {
+					for x in min.x..min.x + size {
+						assert_eq!(octree.get_voxel(UVec3::new(x, y, z)), id);
+						let idx = ((z * 16 + y) * 16 + x) as usize;
+						assert!(!seen[idx], "{x} {y} {z} was yielded twice");
+						seen[idx] = true;
+					}
+				}
+			}
+		}
+		assert!(seen.into_iter().all(|seen| seen));
+	}
+
+	#[test]
+	fn ordered_by_z_then_y_then_x() {
+		let mut octree = Octree::new(4);
+		octree.set_voxel(UVec3::new(1, 0, 0), 1);
+		let leaves: Vec<_> = octree.leaves().collect();
+		assert_eq!(leaves[..3], [(UVec3::ZERO, 1, 0), (UVec3::X, 1, 1), (UVec3::Y, 1, 0)]);
+		assert_eq!(leaves[8], (UVec3::new(2, 0, 0), 2, 0));
+		assert_eq!(leaves.last(), Some(&(UVec3::splat(2), 2, 0)));
+	}
+
+	#[test]
+	fn non_empty() {
+		let octree = octree();
+		let non_empty: Vec<_> = octree.leaves().non_empty().collect();
+		let expected: Vec<_> = octree.leaves().filter(|&(_, _, id)| id != 0).collect();
+		assert_eq!(non_empty, expected);
+		assert!(!non_empty.is_empty());
+
+		assert_eq!(Octree::new(8).leaves().non_empty().next(), None);
+	}
+
+	#[test]
+	fn within() {
+		let octree = octree();
+		let mut rng = Rng(5);
+		for _ in 0..100 {
+			let min = rng.pos(16);
+			let max = min + UVec3::new(rng.next(6) + 1, rng.next(6) + 1, rng.next(6) + 1);
+			let bounds = UAabb::new(min, max);
+
+			let within: Vec<_> = octree.leaves().within(bounds).collect();
+			let expected: Vec<_> = octree
+				.leaves()
+				.filter(|&(min, size, _)| bounds.intersects(&UAabb::new(min, min + size)))
+				.collect();
+			assert_eq!(within, expected);
+			assert!(!within.is_empty());
+
+			// and combined with non_empty
+			let within: Vec<_> = octree.leaves().within(bounds).non_empty().collect();
+			let expected: Vec<_> = expected.into_iter().filter(|&(_, _, id)| id != 0).collect();
+			assert_eq!(within, expected);
+		}
+	}
+
+	#[test]
+	fn within_yields_whole_leaves() {
+		let mut octree = Octree::new(8);
+		octree.fill_box(UVec3::ZERO, UVec3::splat(4), 3);
+		let bounds = UAabb::new(UVec3::new(3, 3, 3), UVec3::new(5, 4, 4));
+		let leaves: Vec<_> = octree.leaves().within(bounds).collect();
+		assert_eq!(leaves, [(UVec3::ZERO, 4, 3), (UVec3::new(4, 0, 0), 4, 0)]);
+
+		// nothing is yielded for a box outside the tree or with no volume
+		let outside = UAabb::new(UVec3::splat(8), UVec3::splat(10));
+		assert_eq!(octree.leaves().within(outside).next(), None);
+		let flat = UAabb::new(UVec3::ZERO, UVec3::new(8, 0, 8));
+		assert_eq!(octree.leaves().within(flat).next(), None);
+	}
+}
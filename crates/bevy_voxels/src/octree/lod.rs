@@ -0,0 +1,90 @@
+use super::{Octree, OctreeValue};
+use bevy::{prelude::*, utils::HashMap};
+
+impl Octree {
+	/// Like [`Octree::extend_raw_data`], but leaves out the bottom `lod` levels of the tree. Every node at the cutoff is
+	/// replaced with a leaf of its representative id, so each level halves the resolution. Only nodes reachable from the
+	/// root are appended.
+	///
+	/// Returns the value for the root, which is a leaf if `lod` removes every level.
+	pub fn extend_lod_data(&self, data: &mut Vec<u32>, lod: u32) -> OctreeValue {
+		let levels = self.size().trailing_zeros();
+		let mut builder = LodBuilder {
+			octree: self,
+			data,
+			nodes: HashMap::new(),
+			representatives: HashMap::new(),
+		};
+		builder.value(OctreeValue::new_pointer(self.entry), levels.saturating_sub(lod))
+	}
+
+	/// The id that stands in for everything under a node when it's drawn as a single leaf. Empty only wins if most of
+	/// the children are empty, so surfaces don't get holes punched in them. Otherwise it's the most common non-empty
+	/// id, with ties going to the upper children, since those are usually the ones in view.
+	fn representative_id(&self, data_idx: u32, representatives: &mut HashMap<u32, u32>) -> u32 {
+		if let Some(&id) = representatives.get(&data_idx) {
+			return id;
+		}
+
+		let node = self.data[data_idx as usize];
+		let mut ids = Vec::with_capacity(8);
+		for y in 0..2 {
+			for z in 0..2 {
+				for x in 0..2 {
+					let value = node.value(UVec3::new(x, y, z));
+					ids.push(match value.pointer_idx() {
+						Some(child_idx) => self.representative_id(child_idx, representatives),
+						None => value.to_u32(),
+					});
+				}
+			}
+		}
+
+		let empty_count = ids.iter().filter(|&&id| id == 0).count();
+		let id = if empty_count > 4 {
+			0
+		} else {
+			// max_by_key returns the last of equal elements, which are the upper children
+			*ids.iter()
+				.filter(|&&id| id != 0)
+				.max_by_key(|&&id| ids.iter().filter(|&&other| other == id).count())
+				.unwrap()
+		};
+		representatives.insert(data_idx, id);
+		id
+	}
+}
+
+struct LodBuilder<'a> {
+	octree: &'a Octree,
+	data: &'a mut Vec<u32>,
+	/// (old idx, levels left) -> new idx, so shared nodes stay shared
+	nodes: HashMap<(u32, u32), u32>,
+	representatives: HashMap<u32, u32>,
+}
+impl LodBuilder<'_> {
+	/// `levels` is how many more levels of nodes to keep under `value`
+	fn value(&mut self, value: OctreeValue, levels: u32) -> OctreeValue {
+		let Some(data_idx) = value.pointer_idx() else {
+			return value;
+		};
+		if levels == 0 {
+			return OctreeValue::new_leaf(self.octree.representative_id(data_idx, &mut self.representatives));
+		}
+		if let Some(&new_idx) = self.nodes.get(&(data_idx, levels)) {
+			return OctreeValue::new_pointer(new_idx);
+		}
+
+		// children go first, so they're all appended before this node's values
+		let node = self.octree.data[data_idx as usize];
+		let mut values = [0; 8];
+		for (i, &child) in node.data.iter().flatten().flatten().enumerate() {
+			values[i] = self.value(child, levels - 1).0;
+		}
+
+		let new_idx = (self.data.len() / 8) as u32;
+		self.data.extend(values);
+		self.nodes.insert((data_idx, levels), new_idx);
+		OctreeValue::new_pointer(new_idx)
+	}
+}
@@ -0,0 +1,79 @@
+use super::Octree;
+use bevy::{prelude::*, utils::HashSet};
+
+/// A summary of what an octree contains
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Occupancy {
+	/// every voxel is empty
+	Empty,
+	/// every voxel has this id, which isn't 0
+	Full(u32),
+	/// a mix of ids, with every non-empty voxel in `min..max`
+	Mixed { min: UVec3, max: UVec3 },
+}
+
+impl Octree {
+	/// Walks every leaf in the tree, so this is about as expensive as copying it
+	pub fn occupancy(&self) -> Occupancy {
+		let mut summary = Summary {
+			id: None,
+			uniform: true,
+			min: UVec3::MAX,
+			max: UVec3::ZERO,
+		};
+		for (min, size, id) in self.leaves() {
+			summary.add_leaf(id, min, size);
+		}
+
+		match summary.id {
+			Some(0) | None if summary.uniform => Occupancy::Empty,
+			Some(id) if summary.uniform => Occupancy::Full(id),
+			_ => Occupancy::Mixed {
+				min: summary.min,
+				max: summary.max,
+			},
+		}
+	}
+
+	/// Every id in the tree other than 0. Nodes used in more than one place are only visited once.
+	pub fn voxel_ids(&self) -> HashSet<u32> {
+		let mut ids = HashSet::new();
+		let mut visited = HashSet::new();
+		let mut stack = vec![self.entry];
+		while let Some(data_idx) = stack.pop() {
+			if !visited.insert(data_idx) {
+				continue;
+			}
+			for value in self.data[data_idx as usize].data.iter().flatten().flatten() {
+				match value.pointer_idx() {
+					Some(child_idx) => stack.push(child_idx),
+					None if value.to_u32() != 0 => {
+						ids.insert(value.to_u32());
+					}
+					None => {}
+				}
+			}
+		}
+		ids
+	}
+}
+
+struct Summary {
+	/// id of the first leaf
+	id: Option<u32>,
+	/// whether every leaf so far has the same id
+	uniform: bool,
+	min: UVec3,
+	max: UVec3,
+}
+impl Summary {
+	fn add_leaf(&mut self, id: u32, min: UVec3, size: u32) {
+		if *self.id.get_or_insert(id) != id {
+			self.uniform = false;
+		}
+		if id != 0 {
+			self.min = self.min.min(min);
+			self.max = self.max.max(min + size);
+		}
+	}
+}
@@ -0,0 +1,267 @@
+use super::Octree;
+use bevy::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RaycastHit {
+	/// position of the voxel that was hit, in the octree's local space
+	pub pos: UVec3,
+	/// normal of the face the ray entered the voxel through, or zero if the ray started inside it
+	pub normal: IVec3,
+	/// distance along the ray to the hit
+	pub distance: f32,
+	pub id: u32,
+}
+
+impl Octree {
+	/// Finds the first non-empty voxel along a ray, within `max_dist` of `origin`. Everything is in the octree's local
+	/// space, where voxel `pos` covers `pos..pos + 1`. `dir` doesn't need to be normalized.
+	///
+	/// This walks the tree the same way `ray_march.wgsl` does, so each step skips a whole leaf, no matter how large.
+	///
+	/// Returns `None` if `dir` is zero, or if `origin` or `dir` aren't finite.
+	pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RaycastHit> {
+		// a NaN direction would make every comparison below false, so the walk would never end
+		if dir.length_squared() == 0.0 || !dir.is_finite() || !origin.is_finite() {
+			return None;
+		}
+		let dir = dir.normalize();
+		let size = Vec3::splat(self.size() as f32);
+		let step = IVec3::new(axis_step(dir.x), axis_step(dir.y), axis_step(dir.z));
+		// axes the ray doesn't move along are never crossed. their t values are NaN or infinite, so mask them out.
+		let parallel = dir.cmpeq(Vec3::ZERO);
+		let inv_dir = dir.recip();
+
+		// find where the ray enters the octree, or start at the origin if it's inside
+		if (parallel & (origin.cmplt(Vec3::ZERO) | origin.cmpge(size))).any() {
+			return None;
+		}
+		let t_planes_0 = -origin * inv_dir;
+		let t_planes_1 = (size - origin) * inv_dir;
+		let t_planes_near = Vec3::select(parallel, Vec3::NEG_INFINITY, t_planes_0.min(t_planes_1));
+		let t_planes_far = Vec3::select(parallel, Vec3::INFINITY, t_planes_0.max(t_planes_1));
+		let t_near = t_planes_near.max_element();
+		let t_far = t_planes_far.min_element();
+		if t_near > t_far || t_far < 0.0 || t_near > max_dist {
+			return None;
+		}
+
+		let mut t = t_near.max(0.0);
+		let mut normal = if t_near > 0.0 {
+			entry_normal(t_planes_near.cmpeq(Vec3::splat(t_near)), step)
+		} else {
+			IVec3::ZERO
+		};
+
+		let mut voxel = (origin + dir * t).floor().clamp(Vec3::ZERO, size - 1.0);
+		// 1 on axes where the ray leaves a cell through its max side, 0 where it leaves through its min side
+		let exit_side = Vec3::select(dir.cmpgt(Vec3::ZERO), Vec3::ONE, Vec3::ZERO);
+
+		loop {
+			let mut cursor = self.voxel_cursor(voxel.as_uvec3());
+			let id = cursor.move_to_leaf().value().voxel_id().unwrap();
+			if id != 0 {
+				return Some(RaycastHit {
+					pos: voxel.as_uvec3(),
+					normal,
+					distance: t,
+					id,
+				});
+			}
+
+			let leaf_size = cursor.quadrant_size() as f32;
+			let leaf_min = (cursor.pos() & !(cursor.quadrant_size() - 1)).as_vec3();
+			let t_planes_far = (leaf_min + exit_side * leaf_size - origin) * inv_dir;
+			let t_planes_far = Vec3::select(parallel, Vec3::INFINITY, t_planes_far);
+			t = t_planes_far.min_element();
+			if t > max_dist {
+				return None;
+			}
+			let mask = t_planes_far.cmpeq(Vec3::splat(t));
+			normal = entry_normal(mask, step);
+
+			// step out of the leaf on the axes we crossed, and stay inside it on the others
+			let crossed = leaf_min + Vec3::select(exit_side.cmpeq(Vec3::ONE), Vec3::splat(leaf_size), Vec3::NEG_ONE);
+			let inside = (origin + dir * t).floor().clamp(leaf_min, leaf_min + leaf_size - 1.0);
+			voxel = Vec3::select(mask, crossed, inside);
+			if voxel.cmplt(Vec3::ZERO).any() || voxel.cmpge(size).any() {
+				return None;
+			}
+		}
+	}
+}
+
+fn axis_step(dir: f32) -> i32 {
+	if dir > 0.0 {
+		1
+	} else if dir < 0.0 {
+		-1
+	} else {
+		0
+	}
+}
+
+/// The normal of the face the ray entered a voxel through. `crossed` is the axes whose planes the ray just crossed. When
+/// it crosses an edge or corner exactly, this picks one of the faces, so the normal always points along a single axis.
+fn entry_normal(crossed: BVec3, step: IVec3) -> IVec3 {
+	if crossed.x {
+		IVec3::new(-step.x, 0, 0)
+	} else if crossed.y {
+		IVec3::new(0, -step.y, 0)
+	} else {
+		IVec3::new(0, 0, -step.z)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn octree() -> Octree {
+		let mut octree = Octree::new(16);
+		octree.set_voxel(UVec3::new(10, 3, 3), 1);
+		octree.set_voxel(UVec3::new(8, 8, 8), 2);
+		octree.fill_box(UVec3::new(0, 12, 0), UVec3::new(16, 4, 16), 3);
+		octree
+	}
+
+	#[test]
+	fn axis_aligned() {
+		let octree = octree();
+		let hit = octree.raycast(Vec3::new(0.5, 3.5, 3.5), Vec3::X, 100.0).unwrap();
+		assert_eq!(hit.pos, UVec3::new(10, 3, 3));
+		assert_eq!(hit.normal, IVec3::NEG_X);
+		assert_eq!(hit.distance, 9.5);
+		assert_eq!(hit.id, 1);
+
+		let hit = octree.raycast(Vec3::new(4.5, 0.5, 4.5), Vec3::Y * 3.0, 100.0).unwrap();
+		assert_eq!(hit.pos, UVec3::new(4, 12, 4));
+		assert_eq!(hit.normal, IVec3::NEG_Y);
+		assert_eq!(hit.distance, 11.5);
+		assert_eq!(hit.id, 3);
+	}
+
+	#[test]
+	fn from_outside() {
+		let octree = octree();
+		let hit = octree.raycast(Vec3::new(20.0, 3.5, 3.5), Vec3::NEG_X, 100.0).unwrap();
+		assert_eq!(hit.pos, UVec3::new(10, 3, 3));
+		assert_eq!(hit.normal, IVec3::X);
+		assert_eq!(hit.distance, 9.0);
+	}
+
+	#[test]
+	fn diagonal() {
+		let octree = octree();
+		let hit = octree.raycast(Vec3::splat(0.5), Vec3::ONE, 100.0).unwrap();
+		assert_eq!(hit.pos, UVec3::splat(8));
+		assert_eq!(hit.id, 2);
+		assert!((hit.distance - 7.5 * 3f32.sqrt()).abs() < 1e-4);
+		// passing exactly through a corner picks one of the faces that meet there
+		assert!([IVec3::NEG_X, IVec3::NEG_Y, IVec3::NEG_Z].contains(&hit.normal));
+	}
+
+	#[test]
+	fn starting_inside() {
+		let octree = octree();
+		let hit = octree.raycast(Vec3::new(10.5, 3.5, 3.5), Vec3::Z, 100.0).unwrap();
+		assert_eq!(hit.pos, UVec3::new(10, 3, 3));
+		assert_eq!(hit.normal, IVec3::ZERO);
+		assert_eq!(hit.distance, 0.0);
+	}
+
+	#[test]
+	fn miss() {
+		let octree = octree();
+		assert_eq!(octree.raycast(Vec3::new(0.5, 0.5, 0.5), Vec3::X, 100.0), None);
+		assert_eq!(octree.raycast(Vec3::new(0.5, 5.5, 0.5), Vec3::NEG_Y, 100.0), None);
+		// pointing away from the octree, and parallel to it outside of it
+		assert_eq!(octree.raycast(Vec3::new(-1.0, 3.5, 3.5), Vec3::NEG_X, 100.0), None);
+		assert_eq!(octree.raycast(Vec3::new(-1.0, 3.5, 3.5), Vec3::Z, 100.0), None);
+	}
+
+	#[test]
+	fn max_dist() {
+		let octree = octree();
+		assert_eq!(octree.raycast(Vec3::new(0.5, 3.5, 3.5), Vec3::X, 9.0), None);
+		assert!(octree.raycast(Vec3::new(0.5, 3.5, 3.5), Vec3::X, 9.5).is_some());
+		// entering the octree past max_dist
+		assert_eq!(octree.raycast(Vec3::new(-20.0, 3.5, 3.5), Vec3::X, 15.0), None);
+	}
+
+	/// The march from `ray_march.wgsl`, step for step, including its 1e30 stand-in for infinity. Returns the first
+	/// non-empty voxel.
+	fn shader_march(octree: &Octree, origin: Vec3, forward: Vec3) -> Option<UVec3> {
+		let bounds_min = Vec3::ZERO;
+		let bounds_max = Vec3::splat(octree.size() as f32);
+		let step = Vec3::select(forward.cmpeq(Vec3::ZERO), Vec3::ZERO, forward.signum());
+		let parallel = step.cmpeq(Vec3::ZERO);
+		let inv_dir = Vec3::select(parallel, Vec3::splat(1e30), forward.recip());
+
+		let t_planes_near = ((bounds_min - origin) * inv_dir).min((bounds_max - origin) * inv_dir);
+		let t = t_planes_near.max_element().max(0.0);
+		let mut voxel = (origin + forward * t).floor().clamp(bounds_min, bounds_max - 1.0);
+		let exit_side = Vec3::select(step.cmpgt(Vec3::ZERO), Vec3::ONE, Vec3::ZERO);
+		loop {
+			let mut cursor = octree.voxel_cursor(voxel.as_uvec3());
+			if cursor.move_to_leaf().value().voxel_id() != Some(0) {
+				return Some(voxel.as_uvec3());
+			}
+
+			let leaf_min = (cursor.pos() & !(cursor.quadrant_size() - 1)).as_vec3();
+			let leaf_size = cursor.quadrant_size() as f32;
+			let t_planes_far = Vec3::select(
+				parallel,
+				Vec3::splat(1e30),
+				(leaf_min + exit_side * leaf_size - origin) * inv_dir,
+			);
+			let t = t_planes_far.min_element();
+			let mask = t_planes_far.cmpeq(Vec3::splat(t));
+
+			let crossed = leaf_min + Vec3::select(exit_side.cmpeq(Vec3::ONE), Vec3::splat(leaf_size), Vec3::NEG_ONE);
+			let inside = (origin + forward * t)
+				.floor()
+				.clamp(leaf_min, leaf_min + leaf_size - 1.0);
+			voxel = Vec3::select(mask, crossed, inside);
+			if voxel.cmplt(bounds_min).any() || voxel.cmpge(bounds_max).any() {
+				return None;
+			}
+		}
+	}
+
+	#[test]
+	fn shader_march_axis_aligned() {
+		let octree = octree();
+		let center = Vec3::splat(8.5);
+		for (origin, forward) in [
+			(Vec3::new(0.5, 3.5, 3.5), Vec3::X),
+			(Vec3::new(20.0, 3.5, 3.5), Vec3::NEG_X),
+			(Vec3::new(4.5, -5.0, 4.5), Vec3::Y),
+			(Vec3::new(8.5, 8.5, -3.0), Vec3::Z),
+			(Vec3::new(8.5, 8.5, 30.0), Vec3::NEG_Z),
+			(center + Vec3::new(0.0, 20.0, 0.0), Vec3::NEG_Y),
+			(Vec3::new(0.5, 0.5, 0.5), Vec3::X),
+			(Vec3::splat(0.5), Vec3::ONE.normalize()),
+		] {
+			let expected = octree.raycast(origin, forward, 100.0).map(|hit| hit.pos);
+			assert_eq!(
+				shader_march(&octree, origin, forward),
+				expected,
+				"from {origin} along {forward}"
+			);
+		}
+		assert_eq!(
+			shader_march(&octree, Vec3::new(0.5, 3.5, 3.5), Vec3::X),
+			Some(UVec3::new(10, 3, 3))
+		);
+	}
+
+	#[test]
+	fn invalid_rays() {
+		let octree = octree();
+		let origin = Vec3::new(0.5, 3.5, 3.5);
+		assert_eq!(octree.raycast(origin, Vec3::ZERO, 100.0), None);
+		assert_eq!(octree.raycast(origin, Vec3::NAN, 100.0), None);
+		assert_eq!(octree.raycast(origin, Vec3::new(f32::INFINITY, 0.0, 0.0), 100.0), None);
+		assert_eq!(octree.raycast(Vec3::NAN, Vec3::X, 100.0), None);
+	}
+}
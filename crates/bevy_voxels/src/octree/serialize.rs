@@ -0,0 +1,379 @@
+//! Binary format for saving and loading octrees. All numbers are little endian.
+//!
+//! | field      | type            |
+//! |------------|-----------------|
+//! | magic      | `b"VOXO"`       |
+//! | version    | u32             |
+//! | size       | u32             |
+//! | position   | i32 x 3         |
+//! | entry      | u32             |
+//! | node count | u32             |
+//! | nodes      | u32 x 8 x count |
+//!
+//! Only nodes reachable from the entry are written, so free ranges are dropped and pointers are renumbered. Nodes are
+//! stored children first, which means every pointer must point to an earlier node. That rules out cycles, so a corrupt
+//! file can't send traversal into an infinite loop.
+//!
+//! Files are only accepted if they're laid out the way edits leave a tree: every node is reachable from the entry, and
+//! no node other than the entry holds eight of the same leaf.
+
+use super::{Octree, OctreeNode, OctreeValue};
+use bevy::{prelude::*, utils::HashMap};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+const MAGIC: [u8; 4] = *b"VOXO";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum ReadOctreeError {
+	#[error("failed to read octree: {0}")]
+	Io(#[from] io::Error),
+	#[error("not an octree file")]
+	InvalidMagic,
+	#[error("unsupported octree format version {0}")]
+	UnsupportedVersion(u32),
+	#[error("octree size {0} is not a power of 2 greater than or equal to 2")]
+	InvalidSize(u32),
+	#[error("entry node {entry} is out of bounds for {node_count} nodes")]
+	InvalidEntry { entry: u32, node_count: u32 },
+	#[error("node {node} points to node {pointer}, which is not stored before it")]
+	InvalidPointer { node: u32, pointer: u32 },
+	#[error("node {0} is subdivided below the size of a single voxel")]
+	TooDeep(u32),
+	#[error("node {0} is a duplicate of node {1}")]
+	DuplicateNode(u32, u32),
+	#[error("node {0} can't be reached from the entry node")]
+	UnreachableNode(u32),
+	#[error("node {0} holds eight of the same leaf, and should have been merged into it")]
+	UnmergedNode(u32),
+}
+
+impl Octree {
+	pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+		let mut new_idxs = HashMap::new();
+		let mut nodes = vec![];
+		self.collect_reachable(self.entry, &mut new_idxs, &mut nodes);
+
+		writer.write_all(&MAGIC)?;
+		write_u32(&mut writer, VERSION)?;
+		write_u32(&mut writer, self.size())?;
+		for coord in self.position.to_array() {
+			writer.write_all(&coord.to_le_bytes())?;
+		}
+		write_u32(&mut writer, new_idxs[&self.entry])?;
+		write_u32(&mut writer, nodes.len() as u32)?;
+		for node in nodes {
+			for value in node.data.iter().flatten().flatten() {
+				write_u32(&mut writer, value.0)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn read_from(mut reader: impl Read) -> Result<Self, ReadOctreeError> {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic)?;
+		if magic != MAGIC {
+			return Err(ReadOctreeError::InvalidMagic);
+		}
+
+		let version = read_u32(&mut reader)?;
+		if version != VERSION {
+			return Err(ReadOctreeError::UnsupportedVersion(version));
+		}
+
+		let size = read_u32(&mut reader)?;
+		if size < 2 || !size.is_power_of_two() {
+			return Err(ReadOctreeError::InvalidSize(size));
+		}
+
+		let position = IVec3::new(
+			read_u32(&mut reader)? as i32,
+			read_u32(&mut reader)? as i32,
+			read_u32(&mut reader)? as i32,
+		);
+		let entry = read_u32(&mut reader)?;
+		let node_count = read_u32(&mut reader)?;
+		if entry >= node_count {
+			return Err(ReadOctreeError::InvalidEntry { entry, node_count });
+		}
+
+		// don't trust node_count for the allocation, a corrupt header could ask for gigabytes
+		let mut data = Vec::with_capacity(node_count.min(4096) as usize);
+		let mut map = HashMap::default();
+		for idx in 0..node_count {
+			let mut node = OctreeNode::new();
+			for value in node.data.iter_mut().flatten().flatten() {
+				*value = OctreeValue(read_u32(&mut reader)?);
+				if let Some(pointer) = value.pointer_idx() {
+					if pointer >= idx {
+						return Err(ReadOctreeError::InvalidPointer { node: idx, pointer });
+					}
+				}
+			}
+
+			if let Some(&(other_idx, _)) = map.get(&node) {
+				return Err(ReadOctreeError::DuplicateNode(idx, other_idx));
+			}
+			map.insert(node, (idx, 0));
+			data.push(node);
+		}
+
+		// pointers can only go backwards, but a node could still be nested deeper than the tree allows
+		let mut depths = vec![None; data.len()];
+		check_depth(&data, &mut depths, entry, size / 2)?;
+
+		// nodes that nothing points to would never be freed, and edits assume uniform nodes were merged into their leaf
+		for (idx, node) in data.iter().enumerate() {
+			if depths[idx].is_none() {
+				return Err(ReadOctreeError::UnreachableNode(idx as u32));
+			}
+			if idx as u32 != entry && node.uniform_leaf().is_some() {
+				return Err(ReadOctreeError::UnmergedNode(idx as u32));
+			}
+		}
+
+		// refcounts are the number of values pointing to a node, plus one for the entry
+		map.get_mut(&data[entry as usize]).unwrap().1 += 1;
+		for node in &data {
+			for pointer in node
+				.data
+				.iter()
+				.flatten()
+				.flatten()
+				.filter_map(|value| value.pointer_idx())
+			{
+				map.get_mut(&data[pointer as usize]).unwrap().1 += 1;
+			}
+		}
+
+		Ok(Self {
+			quadrant_size: size / 2,
+			position,
+			entry,
+			data,
+			map,
+			free_ranges: vec![],
+		})
+	}
+
+	/// Appends every node reachable from `data_idx` to `nodes`, children first, and records where each one ended up
+	fn collect_reachable(&self, data_idx: u32, new_idxs: &mut HashMap<u32, u32>, nodes: &mut Vec<OctreeNode>) {
+		if new_idxs.contains_key(&data_idx) {
+			return;
+		}
+
+		let mut node = self.data[data_idx as usize];
+		for value in node.data.iter_mut().flatten().flatten() {
+			if let Some(child_idx) = value.pointer_idx() {
+				self.collect_reachable(child_idx, new_idxs, nodes);
+				*value = OctreeValue::new_pointer(new_idxs[&child_idx]);
+			}
+		}
+
+		new_idxs.insert(data_idx, nodes.len() as u32);
+		nodes.push(node);
+	}
+}
+
+/// `depths` holds the smallest quadrant size each node has been checked at. A node that's fine at some quadrant size is
+/// also fine at any larger one, so it only needs to be revisited when it shows up deeper in the tree.
+fn check_depth(
+	data: &[OctreeNode],
+	depths: &mut [Option<u32>],
+	data_idx: u32,
+	quadrant_size: u32,
+) -> Result<(), ReadOctreeError> {
+	if depths[data_idx as usize].is_some_and(|checked_size| checked_size <= quadrant_size) {
+		return Ok(());
+	}
+	depths[data_idx as usize] = Some(quadrant_size);
+
+	for value in data[data_idx as usize].data.iter().flatten().flatten() {
+		if let Some(child_idx) = value.pointer_idx() {
+			if quadrant_size == 1 {
+				return Err(ReadOctreeError::TooDeep(data_idx));
+			}
+			check_depth(data, depths, child_idx, quadrant_size / 2)?;
+		}
+	}
+
+	Ok(())
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+	writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+	let mut bytes = [0; 4];
+	reader.read_exact(&mut bytes)?;
+	Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A file with the given header fields and nodes, each listed as raw values
+	fn file(version: u32, size: u32, entry: u32, nodes: &[[u32; 8]]) -> Vec<u8> {
+		let mut bytes = MAGIC.to_vec();
+		for value in [version, size, 1, 2, 3, entry, nodes.len() as u32] {
+			bytes.extend(value.to_le_bytes());
+		}
+		for value in nodes.iter().flatten() {
+			bytes.extend(value.to_le_bytes());
+		}
+		bytes
+	}
+
+	fn leaf(id: u32) -> u32 {
+		OctreeValue::new_leaf(id).0
+	}
+
+	fn pointer(idx: u32) -> u32 {
+		OctreeValue::new_pointer(idx).0
+	}
+
+	fn read(bytes: &[u8]) -> Result<Octree, ReadOctreeError> {
+		Octree::read_from(bytes)
+	}
+
+	#[test]
+	fn round_trip() {
+		let mut octree = Octree::new(16);
+		octree.set_position(IVec3::new(-32, 16, 48));
+		octree.fill_box(UVec3::new(2, 0, 3), UVec3::new(9, 5, 13), 4);
+		octree.set_voxel(UVec3::new(15, 15, 15), 7);
+		octree.set_voxel(UVec3::new(4, 2, 5), 0);
+		// leaves some free nodes behind, which aren't written
+		octree.fill_box(UVec3::new(8, 8, 8), UVec3::splat(8), 2);
+
+		let mut bytes = vec![];
+		octree.write_to(&mut bytes).unwrap();
+		let loaded = read(&bytes).unwrap();
+
+		assert_eq!(loaded.size(), 16);
+		assert_eq!(loaded.get_position(), octree.get_position());
+		assert_eq!(loaded.leaves().collect::<Vec<_>>(), octree.leaves().collect::<Vec<_>>());
+		assert!(loaded.free_ranges.is_empty());
+
+		let mut again = vec![];
+		loaded.write_to(&mut again).unwrap();
+		assert_eq!(again, bytes);
+	}
+
+	#[test]
+	fn round_trip_refcounts() {
+		let mut octree = Octree::new(8);
+		octree.set_voxel(UVec3::ZERO, 1);
+		octree.set_voxel(UVec3::new(4, 0, 0), 1);
+
+		let mut bytes = vec![];
+		octree.write_to(&mut bytes).unwrap();
+		let mut loaded = read(&bytes).unwrap();
+
+		// both quadrants share one node, so clearing one of them has to keep it alive for the other
+		loaded.set_voxel(UVec3::ZERO, 0);
+		assert_eq!(loaded.get_voxel(UVec3::ZERO), 0);
+		assert_eq!(loaded.get_voxel(UVec3::new(4, 0, 0)), 1);
+		loaded.set_voxel(UVec3::new(4, 0, 0), 0);
+		assert_eq!(loaded.leaves().non_empty().count(), 0);
+		assert_eq!(loaded.map.len(), 1);
+	}
+
+	#[test]
+	fn truncated() {
+		let bytes = file(VERSION, 2, 0, &[[leaf(1); 8]]);
+		let err = read(&bytes[..bytes.len() - 1]).unwrap_err();
+		assert!(matches!(err, ReadOctreeError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+	}
+
+	#[test]
+	fn invalid_magic() {
+		let mut bytes = file(VERSION, 2, 0, &[[leaf(1); 8]]);
+		bytes[0] = b'X';
+		assert!(matches!(read(&bytes), Err(ReadOctreeError::InvalidMagic)));
+	}
+
+	#[test]
+	fn unsupported_version() {
+		let bytes = file(VERSION + 1, 2, 0, &[[leaf(1); 8]]);
+		assert!(matches!(read(&bytes), Err(ReadOctreeError::UnsupportedVersion(v)) if v == VERSION + 1));
+	}
+
+	#[test]
+	fn invalid_size() {
+		for size in [0, 1, 6] {
+			let bytes = file(VERSION, size, 0, &[[leaf(1); 8]]);
+			assert!(matches!(read(&bytes), Err(ReadOctreeError::InvalidSize(s)) if s == size));
+		}
+	}
+
+	#[test]
+	fn invalid_entry() {
+		let bytes = file(VERSION, 2, 1, &[[leaf(1); 8]]);
+		assert!(matches!(
+			read(&bytes),
+			Err(ReadOctreeError::InvalidEntry {
+				entry: 1,
+				node_count: 1
+			})
+		));
+	}
+
+	#[test]
+	fn invalid_pointer() {
+		// a node pointing to itself would be a cycle
+		let mut node = [leaf(0); 8];
+		node[0] = pointer(0);
+		let bytes = file(VERSION, 4, 0, &[node]);
+		assert!(matches!(
+			read(&bytes),
+			Err(ReadOctreeError::InvalidPointer { node: 0, pointer: 0 })
+		));
+	}
+
+	#[test]
+	fn too_deep() {
+		let mut child = [leaf(0); 8];
+		child[0] = leaf(1);
+		let mut root = [leaf(0); 8];
+		root[0] = pointer(0);
+		let bytes = file(VERSION, 2, 1, &[child, root]);
+		assert!(matches!(read(&bytes), Err(ReadOctreeError::TooDeep(1))));
+	}
+
+	#[test]
+	fn duplicate_node() {
+		let mut child = [leaf(0); 8];
+		child[0] = leaf(1);
+		let mut root = [leaf(0); 8];
+		root[0] = pointer(0);
+		root[1] = pointer(1);
+		let bytes = file(VERSION, 4, 2, &[child, child, root]);
+		assert!(matches!(read(&bytes), Err(ReadOctreeError::DuplicateNode(1, 0))));
+	}
+
+	#[test]
+	fn unreachable_node() {
+		let mut orphan = [leaf(0); 8];
+		orphan[0] = leaf(1);
+		let bytes = file(VERSION, 4, 1, &[orphan, [leaf(2); 8]]);
+		assert!(matches!(read(&bytes), Err(ReadOctreeError::UnreachableNode(0))));
+	}
+
+	#[test]
+	fn unmerged_node() {
+		let mut root = [leaf(0); 8];
+		root[0] = pointer(0);
+		let bytes = file(VERSION, 4, 1, &[[leaf(3); 8], root]);
+		assert!(matches!(read(&bytes), Err(ReadOctreeError::UnmergedNode(0))));
+
+		// the entry is allowed to be uniform, since the root always stays a node
+		let bytes = file(VERSION, 4, 0, &[[leaf(3); 8]]);
+		assert_eq!(read(&bytes).unwrap().get_voxel(UVec3::new(3, 3, 3)), 3);
+	}
+}
@@ -0,0 +1,192 @@
+use crate::{generator::TerrainGenerator, region::RegionMaterial};
+use bevy::{
+	prelude::*,
+	render::{
+		render_resource::{Buffer, ShaderType, StorageBuffer},
+		renderer::{RenderDevice, RenderQueue},
+	},
+};
+
+/// How each voxel id is shaded. Modify this resource to change the look of voxels at runtime.
+///
+/// Ids the palette doesn't have a material for are shaded with its fallback material.
+#[derive(Clone, Debug, Resource)]
+pub struct VoxelPalette {
+	/// index 0 is the fallback, since id 0 is empty and never shaded
+	materials: Vec<VoxelMaterial>,
+}
+impl VoxelPalette {
+	pub fn new(fallback: impl Into<VoxelMaterial>) -> Self {
+		Self {
+			materials: vec![fallback.into()],
+		}
+	}
+
+	/// Gives the voxel with id `i` the `i`th color, skipping the first one since id 0 is empty. This fits the palette
+	/// of a [`VoxModel`](crate::vox::VoxModel).
+	pub fn from_colors(colors: impl IntoIterator<Item = Color>) -> Self {
+		let mut palette = Self::new(VoxelMaterial::default());
+		palette
+			.materials
+			.extend(colors.into_iter().skip(1).map(VoxelMaterial::from));
+		palette
+	}
+
+	pub fn get(&self, id: u32) -> &VoxelMaterial {
+		self.materials.get(id as usize).unwrap_or(&self.materials[0])
+	}
+
+	/// `id` must not be 0
+	pub fn set(&mut self, id: u32, material: impl Into<VoxelMaterial>) {
+		assert_ne!(id, 0, "id 0 is empty and can't have a material");
+
+		let fallback = self.materials[0];
+		let idx = id as usize;
+		if idx >= self.materials.len() {
+			self.materials.resize(idx + 1, fallback);
+		}
+		self.materials[idx] = material.into();
+	}
+
+	pub fn fallback(&self) -> &VoxelMaterial {
+		&self.materials[0]
+	}
+
+	pub fn set_fallback(&mut self, material: impl Into<VoxelMaterial>) {
+		self.materials[0] = material.into();
+	}
+}
+impl Default for VoxelPalette {
+	/// Covers the voxels placed by [`TerrainGenerator`]
+	fn default() -> Self {
+		let mut palette = Self::new(VoxelMaterial::default());
+		palette.set(
+			TerrainGenerator::STONE,
+			VoxelMaterial {
+				base_color: Color::rgb(0.5, 0.5, 0.5),
+				perceptual_roughness: 0.9,
+				..default()
+			},
+		);
+		palette.set(
+			TerrainGenerator::DIRT,
+			VoxelMaterial {
+				base_color: Color::rgb(0.45, 0.3, 0.18),
+				perceptual_roughness: 1.0,
+				..default()
+			},
+		);
+		palette.set(
+			TerrainGenerator::GRASS,
+			VoxelMaterial {
+				base_color: Color::rgb(0.3, 0.6, 0.2),
+				perceptual_roughness: 0.8,
+				..default()
+			},
+		);
+		palette
+	}
+}
+
+/// The PBR properties of a voxel id. These work like the fields of the same name on [`StandardMaterial`].
+///
+/// Materials with a `base_color` alpha below 1 are translucent, like water or glass. Rays keep going through them to
+/// the next opaque voxel, picking up their color and losing `alpha` of their light for every voxel they travel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelMaterial {
+	pub base_color: Color,
+	pub emissive: Color,
+	pub perceptual_roughness: f32,
+	pub metallic: f32,
+	/// Index of refraction, which bends rays going into and out of translucent voxels. Around 1.33 for water and 1.5
+	/// for glass.
+	pub ior: f32,
+	/// How far light from voxels with this material spreads, in voxels, up to
+	/// [`MAX_LIGHT_LEVEL`](crate::light::MAX_LIGHT_LEVEL). The light takes on the color of `emissive`, so lamps and lava
+	/// should glow too. 0 for voxels that don't give off light.
+	pub light: u8,
+}
+impl VoxelMaterial {
+	/// Whether voxels with this material can be seen through, which is when `base_color` isn't fully opaque
+	pub fn is_translucent(&self) -> bool {
+		self.base_color.a() < 1.0
+	}
+}
+impl Default for VoxelMaterial {
+	fn default() -> Self {
+		Self {
+			base_color: Color::WHITE,
+			emissive: Color::BLACK,
+			perceptual_roughness: 0.5,
+			metallic: 0.0,
+			ior: 1.0,
+			light: 0,
+		}
+	}
+}
+impl From<Color> for VoxelMaterial {
+	fn from(base_color: Color) -> Self {
+		Self {
+			base_color,
+			..default()
+		}
+	}
+}
+
+/// The palette as it's laid out in `ray_march.wgsl`
+#[derive(Clone, Copy, Debug, ShaderType)]
+struct GpuVoxelMaterial {
+	base_color: Vec4,
+	emissive: Vec4,
+	perceptual_roughness: f32,
+	metallic: f32,
+	ior: f32,
+}
+impl From<&VoxelMaterial> for GpuVoxelMaterial {
+	fn from(material: &VoxelMaterial) -> Self {
+		Self {
+			base_color: material.base_color.as_linear_rgba_f32().into(),
+			emissive: material.emissive.as_linear_rgba_f32().into(),
+			perceptual_roughness: material.perceptual_roughness,
+			metallic: material.metallic,
+			ior: material.ior,
+		}
+	}
+}
+
+/// One buffer shared by every region's material
+#[derive(Default, Resource)]
+pub(crate) struct PaletteBuffer(StorageBuffer<Vec<GpuVoxelMaterial>>);
+impl PaletteBuffer {
+	/// Only valid after `prepare_palette` has run once
+	pub(crate) fn buffer(&self) -> &Buffer {
+		self.0.buffer().unwrap()
+	}
+}
+
+/// Uploads the palette whenever it changes
+pub(crate) fn prepare_palette(
+	palette: Res<VoxelPalette>,
+	mut palette_buffer: ResMut<PaletteBuffer>,
+	render_device: Res<RenderDevice>,
+	render_queue: Res<RenderQueue>,
+	mut materials: ResMut<Assets<RegionMaterial>>,
+) {
+	if !palette.is_changed() {
+		return;
+	}
+
+	let old_buffer_id = palette_buffer.0.buffer().map(|buffer| buffer.id());
+	palette_buffer
+		.0
+		.set(palette.materials.iter().map(GpuVoxelMaterial::from).collect());
+	palette_buffer.0.write_buffer(&render_device, &render_queue);
+
+	// the buffer is only replaced when the palette grows, but then every region needs to be pointed at the new one
+	let buffer = palette_buffer.buffer();
+	if old_buffer_id != Some(buffer.id()) {
+		for (_, material) in materials.iter_mut() {
+			material.palette = buffer.clone();
+		}
+	}
+}
@@ -0,0 +1,387 @@
+use crate::{
+	light::ChunkLight,
+	octree::{Occupancy, Octree, OctreeValue},
+	palette::{PaletteBuffer, VoxelPalette},
+	AmbientOcclusion, Chunk, ChunkData, ChunkLod, ChunkSize, ChunkSummary, LightIlluminance, LoadedChunks,
+};
+use bevy::{
+	pbr::{MaterialPipeline, MaterialPipelineKey},
+	prelude::*,
+	reflect::TypePath,
+	render::{
+		mesh::MeshVertexBufferLayout,
+		render_resource::{
+			AsBindGroup, Buffer, Face, RenderPipelineDescriptor, ShaderRef, ShaderType, SpecializedMeshPipelineError,
+		},
+	},
+	utils::{HashMap, HashSet},
+};
+
+/// Chunks are drawn in cubes of `size`³ chunks called regions. Each region is a single box with a single material that
+/// holds every chunk in it, so rays can cross from one chunk to the next without leaving the shader. That means one
+/// fragment per pixel per region, instead of one per chunk. Regions with nothing in them aren't drawn at all.
+#[derive(Resource)]
+pub(crate) struct Regions {
+	/// chunks along each edge of a region
+	size: u32,
+	loaded: HashMap<IVec3, LoadedRegion>,
+	/// regions that need to be uploaded again at the end of the frame
+	dirty: HashSet<IVec3>,
+}
+impl Regions {
+	pub(crate) fn new(size: u32) -> Self {
+		Self {
+			size,
+			loaded: HashMap::new(),
+			dirty: HashSet::new(),
+		}
+	}
+
+	/// Call when a chunk is added, changed, or removed. The whole region is uploaded again, not just the chunk.
+	pub(crate) fn mark_dirty(&mut self, chunk_position: IVec3) {
+		self.dirty
+			.insert(chunk_position.div_euclid(IVec3::splat(self.size as i32)));
+	}
+
+	/// The LOD level of every chunk in a region, ordered by z, then y, then x, based on the distance to the nearest
+	/// camera
+	fn chunk_lods(&self, region_position: IVec3, chunk_size: u32, lod: &ChunkLod, cameras: &[Vec3]) -> Vec<u32> {
+		let first_chunk = region_position * self.size as i32;
+		let mut lods = Vec::with_capacity(self.size.pow(3) as usize);
+		for z in 0..self.size {
+			for y in 0..self.size {
+				for x in 0..self.size {
+					let chunk_min = ((first_chunk + UVec3::new(x, y, z).as_ivec3()) * chunk_size as i32).as_vec3();
+					let chunk_max = chunk_min + chunk_size as f32;
+					let distance = cameras
+						.iter()
+						.map(|&camera| camera.distance(camera.clamp(chunk_min, chunk_max)))
+						.fold(f32::INFINITY, f32::min);
+					lods.push(lod.level(distance));
+				}
+			}
+		}
+		lods
+	}
+}
+
+struct LoadedRegion {
+	entity: Entity,
+	/// what `Regions::chunk_lods` returned when the region was last uploaded
+	lods: Vec<u32>,
+	/// every non-empty id in the region
+	ids: HashSet<u32>,
+	translucent: bool,
+}
+
+/// Regions with any translucent voxels are drawn in the transparent pass, which is sorted and doesn't write depth. The
+/// rest are drawn in the opaque passes, which also lets them take part in the depth prepass, SSAO, and so on.
+fn is_translucent(ids: &HashSet<u32>, palette: &VoxelPalette) -> bool {
+	ids.iter().any(|&id| palette.get(id).is_translucent())
+}
+
+/// A cube from 0 to 1, which gets scaled to fit the non-empty part of each region
+#[derive(Resource)]
+pub(crate) struct RegionBox {
+	mesh: Handle<Mesh>,
+}
+impl RegionBox {
+	pub(crate) fn new(meshes: &mut Assets<Mesh>) -> Self {
+		let mut cube = Cuboid::from_size(Vec3::ONE).mesh();
+		cube.translate_by(Vec3::splat(0.5));
+
+		let mesh = meshes.add(cube);
+		Self { mesh }
+	}
+}
+
+/// Sends the regions of new, edited, and unloaded chunks to the GPU. This runs once per frame, so any number of changes
+/// to a region in the same frame only upload it once.
+///
+/// A region's chunks are packed end to end in one node buffer and one light buffer, so a chunk that changes size moves
+/// every chunk after it. Rather than track that, a region with any change is rebuilt and uploaded whole: setting one
+/// voxel uploads the nodes and light of all `size`³ chunks around it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn upload_regions(
+	mut commands: Commands,
+	mut regions: ResMut<Regions>,
+	mut materials: ResMut<Assets<RegionMaterial>>,
+	palette: Res<VoxelPalette>,
+	palette_buffer: Res<PaletteBuffer>,
+	region_box: Res<RegionBox>,
+	chunk_size: Res<ChunkSize>,
+	lod: Res<ChunkLod>,
+	ambient_occlusion: Res<AmbientOcclusion>,
+	light_illuminance: Res<LightIlluminance>,
+	loaded_chunks: Res<LoadedChunks>,
+	cameras: Query<&GlobalTransform, With<Camera>>,
+	changed_chunks: Query<&Chunk, Changed<ChunkData>>,
+	chunks: Query<(&ChunkData, &ChunkSummary, &ChunkLight)>,
+	mut region_entities: Query<(&Handle<RegionMaterial>, &mut Transform)>,
+) {
+	for chunk in changed_chunks.iter() {
+		regions.mark_dirty(chunk.position);
+	}
+
+	// chunks change detail as the cameras move
+	let cameras: Vec<_> = cameras.iter().map(GlobalTransform::translation).collect();
+	let regions = &mut *regions;
+	for (&region_position, region) in &regions.loaded {
+		if region.lods != regions.chunk_lods(region_position, **chunk_size, &lod, &cameras) {
+			regions.dirty.insert(region_position);
+		}
+	}
+
+	// palette changes can move regions between the opaque and transparent passes
+	if palette.is_changed() {
+		for (&region_position, region) in &regions.loaded {
+			if region.translucent != is_translucent(&region.ids, &palette) {
+				regions.dirty.insert(region_position);
+			}
+		}
+	}
+
+	for region_position in regions.dirty.drain().collect::<Vec<_>>() {
+		let first_chunk = region_position * regions.size as i32;
+		let lods = regions.chunk_lods(region_position, **chunk_size, &lod, &cameras);
+
+		// gather every chunk in the region that has finished generating
+		let mut region_chunks = vec![];
+		let mut ids = HashSet::new();
+		for z in 0..regions.size {
+			for y in 0..regions.size {
+				for x in 0..regions.size {
+					let chunk_position = first_chunk + UVec3::new(x, y, z).as_ivec3();
+					let chunk = loaded_chunks
+						.ready(chunk_position)
+						.and_then(|entity| chunks.get(entity).ok());
+					if let Some((_, summary, _)) = chunk {
+						ids.extend(&summary.ids);
+					}
+					region_chunks.push(
+						chunk.map(|(data, summary, light)| (&data.0, summary.occupancy, light.levels.as_slice())),
+					);
+				}
+			}
+		}
+
+		let origin = first_chunk * **chunk_size as i32;
+		let translucent = is_translucent(&ids, &palette);
+		let Some(material) = RegionMaterial::new(
+			origin,
+			regions.size,
+			**chunk_size,
+			&region_chunks,
+			&lods,
+			**ambient_occlusion,
+			**light_illuminance,
+			translucent,
+			palette_buffer.buffer(),
+		) else {
+			if let Some(region) = regions.loaded.remove(&region_position) {
+				commands.entity(region.entity).despawn();
+			}
+			continue;
+		};
+
+		// only draw the part of the region that has something in it
+		let transform = Transform {
+			translation: (origin + material.region.bounds_min.as_ivec3()).as_vec3(),
+			scale: (material.region.bounds_max - material.region.bounds_min).as_vec3(),
+			..default()
+		};
+		match regions.loaded.get_mut(&region_position) {
+			Some(region) => {
+				let (handle, mut region_transform) = region_entities.get_mut(region.entity).unwrap();
+				*materials.get_mut(handle).unwrap() = material;
+				*region_transform = transform;
+				region.lods = lods;
+				region.ids = ids;
+				region.translucent = translucent;
+			}
+			None => {
+				let entity = commands.spawn(MaterialMeshBundle {
+					mesh: region_box.mesh.clone(),
+					material: materials.add(material),
+					transform,
+					..default()
+				});
+				let entity = entity.id();
+				regions.loaded.insert(
+					region_position,
+					LoadedRegion {
+						entity,
+						lods,
+						ids,
+						translucent,
+					},
+				);
+			}
+		}
+	}
+}
+
+#[derive(Asset, AsBindGroup, TypePath, Debug, Clone)]
+#[bind_group_data(RegionMaterialKey)]
+pub(crate) struct RegionMaterial {
+	#[uniform(0)]
+	region: GpuRegion,
+	/// one `OctreeValue` per chunk, which is either a pointer to its root node in `nodes` or a single leaf for chunks
+	/// that are all one id. chunks that aren't loaded are empty leaves.
+	#[storage(1, read_only)]
+	chunks: Vec<u32>,
+	/// the nodes of every chunk in the region, one after another
+	#[storage(2, read_only)]
+	nodes: Vec<u32>,
+	#[storage(3, read_only, buffer)]
+	pub(crate) palette: Buffer,
+	/// where each chunk's `ChunkLight` starts in the rest of the buffer, or `u32::MAX` for chunks that are dark,
+	/// followed by the levels of every chunk that isn't
+	#[storage(4, read_only)]
+	light: Vec<u32>,
+	ambient_occlusion: bool,
+	translucent: bool,
+}
+impl RegionMaterial {
+	/// `region_chunks` has one entry per chunk in the region, ordered by z, then y, then x, which is `None` for chunks
+	/// that aren't loaded. Each has the chunk's voxels, occupancy, and `ChunkLight` levels. `lods` is the LOD level of
+	/// each chunk, in the same order. Returns `None` if every voxel in the region is empty.
+	#[allow(clippy::too_many_arguments)]
+	fn new(
+		origin: IVec3,
+		region_size: u32,
+		chunk_size: u32,
+		region_chunks: &[Option<(&Octree, Occupancy, &[u32])>],
+		lods: &[u32],
+		ambient_occlusion: bool,
+		light_illuminance: f32,
+		translucent: bool,
+		palette: &Buffer,
+	) -> Option<Self> {
+		let mut chunks = Vec::with_capacity(region_chunks.len());
+		let mut nodes = vec![];
+		let mut light = vec![u32::MAX; region_chunks.len()];
+		let mut bounds: Option<(UVec3, UVec3)> = None;
+		for (i, chunk) in region_chunks.iter().enumerate() {
+			let i = i as u32;
+			let chunk_min =
+				UVec3::new(i % region_size, i / region_size % region_size, i / region_size.pow(2)) * chunk_size;
+
+			// uniform chunks don't need any nodes
+			let (value, occupied) = match *chunk {
+				None | Some((_, Occupancy::Empty, _)) => (OctreeValue::new_leaf(0), None),
+				Some((_, Occupancy::Full(id), _)) => {
+					(OctreeValue::new_leaf(id), Some((UVec3::ZERO, UVec3::splat(chunk_size))))
+				}
+				Some((octree, Occupancy::Mixed { min, max }, _)) => {
+					let value = match lods[i as usize] {
+						0 => OctreeValue::new_pointer(octree.extend_raw_data(&mut nodes)),
+						lod => octree.extend_lod_data(&mut nodes, lod),
+					};
+					(value, Some((min, max)))
+				}
+			};
+			chunks.push(value.0);
+
+			if let Some((_, _, levels)) = chunk {
+				if !levels.is_empty() {
+					light[i as usize] = light.len() as u32;
+					light.extend_from_slice(levels);
+				}
+			}
+
+			if let Some((min, max)) = occupied {
+				let (bounds_min, bounds_max) = bounds.get_or_insert((UVec3::MAX, UVec3::ZERO));
+				*bounds_min = bounds_min.min(chunk_min + min);
+				*bounds_max = bounds_max.max(chunk_min + max);
+			}
+		}
+		let (bounds_min, bounds_max) = bounds?;
+
+		// bindings can't be empty, which happens when every chunk is uniform
+		if nodes.is_empty() {
+			nodes.extend([0; 8]);
+		}
+
+		Some(Self {
+			region: GpuRegion {
+				origin,
+				chunk_size,
+				bounds_min,
+				region_size,
+				bounds_max,
+				light_illuminance,
+			},
+			chunks,
+			nodes,
+			palette: palette.clone(),
+			light,
+			ambient_occlusion,
+			translucent,
+		})
+	}
+}
+impl Material for RegionMaterial {
+	fn prepass_fragment_shader() -> ShaderRef {
+		"shaders/voxels_prepass.wgsl".into()
+	}
+
+	fn fragment_shader() -> ShaderRef {
+		"shaders/voxels.wgsl".into()
+	}
+
+	fn alpha_mode(&self) -> AlphaMode {
+		// rays that miss discard, so even opaque regions need a mask. that also makes the prepass and shadow passes use
+		// the ray marching fragment shader instead of the depth of the box.
+		if self.translucent {
+			AlphaMode::Blend
+		} else {
+			AlphaMode::Mask(0.5)
+		}
+	}
+
+	fn specialize(
+		_pipeline: &MaterialPipeline<Self>,
+		descriptor: &mut RenderPipelineDescriptor,
+		_layout: &MeshVertexBufferLayout,
+		key: MaterialPipelineKey<Self>,
+	) -> Result<(), SpecializedMeshPipelineError> {
+		// the shader finds where the ray enters the region on its own, so only the back faces are drawn. that covers
+		// every pixel the region is in exactly once, even with the camera inside it.
+		descriptor.primitive.cull_mode = Some(Face::Front);
+
+		if key.bind_group_data.ambient_occlusion {
+			if let Some(fragment) = &mut descriptor.fragment {
+				fragment.shader_defs.push("VOXEL_AMBIENT_OCCLUSION".into());
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Settings that need a different pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct RegionMaterialKey {
+	ambient_occlusion: bool,
+}
+impl From<&RegionMaterial> for RegionMaterialKey {
+	fn from(material: &RegionMaterial) -> Self {
+		Self {
+			ambient_occlusion: material.ambient_occlusion,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, ShaderType)]
+struct GpuRegion {
+	/// world position of the region's corner, in voxels
+	origin: IVec3,
+	chunk_size: u32,
+	/// every non-empty voxel is in `bounds_min..bounds_max`, relative to `origin`
+	bounds_min: UVec3,
+	region_size: u32,
+	bounds_max: UVec3,
+	/// [`VoxelRenderPlugin::light_illuminance`](crate::VoxelRenderPlugin::light_illuminance)
+	light_illuminance: f32,
+}
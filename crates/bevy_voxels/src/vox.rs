@@ -0,0 +1,579 @@
+//! Importer for MagicaVoxel `.vox` files, based on
+//! https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt and
+//! https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox-extension.txt
+//!
+//! Every model in the scene is placed with its `nTRN` transforms and written into a single [`Octree`]. Palette indices
+//! are used directly as voxel ids, so id 0 stays empty and ids 1 to 255 index into [`VoxModel::palette`].
+
+use crate::octree::Octree;
+use bevy::{
+	asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+	prelude::*,
+	utils::{BoxedFuture, HashMap, HashSet},
+};
+use std::io;
+use thiserror::Error;
+
+#[derive(Asset, TypePath, Debug)]
+pub struct VoxModel {
+	/// The smallest octree that fits the whole scene, with the minimum corner of the scene at the origin
+	pub octree: Octree,
+	/// Indexed by voxel id. Entry 0 is never used.
+	pub palette: [Color; 256],
+}
+
+#[derive(Debug, Error)]
+pub enum VoxError {
+	#[error("failed to read vox file: {0}")]
+	Io(#[from] io::Error),
+	#[error("not a vox file")]
+	InvalidMagic,
+	#[error("vox file ended in the middle of a chunk")]
+	UnexpectedEof,
+	#[error("vox file has no MAIN chunk")]
+	MissingMain,
+	#[error("model {0} does not exist")]
+	InvalidModel(i32),
+	#[error("scene graph node {0} does not exist or is used more than once")]
+	InvalidNode(i32),
+	#[error("invalid rotation {0:?}")]
+	InvalidRotation(String),
+	#[error("invalid translation {0:?}")]
+	InvalidTranslation(String),
+	#[error("models are placed too far apart to fit in an octree")]
+	TooLarge,
+}
+
+/// Loads a `.vox` file into a [`VoxModel`]
+pub fn load_vox(bytes: &[u8]) -> Result<VoxModel, VoxError> {
+	let mut reader = ByteReader(bytes);
+	if reader.take(4)? != b"VOX " {
+		return Err(VoxError::InvalidMagic);
+	}
+	let _version = reader.i32()?;
+
+	let (id, _, mut children) = reader.chunk()?;
+	if id != b"MAIN" {
+		return Err(VoxError::MissingMain);
+	}
+
+	let mut sizes = vec![];
+	let mut models = vec![];
+	let mut palette = default_palette();
+	let mut nodes = HashMap::new();
+	let mut hidden_layers = vec![];
+	while !children.0.is_empty() {
+		let (id, mut content, _) = children.chunk()?;
+		match id {
+			b"SIZE" => sizes.push(UVec3::new(content.u32()?, content.u32()?, content.u32()?)),
+			b"XYZI" => {
+				let count = content.u32()?;
+				let mut voxels = Vec::with_capacity(count.min(1 << 20) as usize);
+				for _ in 0..count {
+					let voxel = content.take(4)?;
+					voxels.push((UVec3::new(voxel[0] as _, voxel[1] as _, voxel[2] as _), voxel[3]));
+				}
+				models.push(voxels);
+			}
+			b"RGBA" => {
+				// the chunk stores colors for indices 1 to 255, followed by an unused entry
+				for color in palette.iter_mut().skip(1) {
+					let rgba = content.take(4)?;
+					*color = Color::rgba_u8(rgba[0], rgba[1], rgba[2], rgba[3]);
+				}
+			}
+			b"nTRN" => {
+				let node_id = content.i32()?;
+				let attributes = content.dict()?;
+				let child = content.i32()?;
+				let _reserved = content.i32()?;
+				let layer = content.i32()?;
+				let frame_count = content.i32()?;
+				// animations aren't supported, so only the first frame is used
+				let frame = if frame_count > 0 {
+					content.dict()?
+				} else {
+					HashMap::new()
+				};
+
+				let transform = VoxTransform::parse(&frame)?;
+				let hidden = attributes.get("_hidden").is_some_and(|hidden| hidden == "1");
+				nodes.insert(
+					node_id,
+					SceneNode::Transform {
+						transform,
+						child,
+						layer,
+						hidden,
+					},
+				);
+			}
+			b"nGRP" => {
+				let node_id = content.i32()?;
+				let _attributes = content.dict()?;
+				let child_count = content.i32()?;
+				let group_children = (0..child_count).map(|_| content.i32()).collect::<Result<_, _>>()?;
+				nodes.insert(node_id, SceneNode::Group(group_children));
+			}
+			b"nSHP" => {
+				let node_id = content.i32()?;
+				let _attributes = content.dict()?;
+				let model_count = content.i32()?;
+				let mut shape_models = vec![];
+				for _ in 0..model_count {
+					shape_models.push(content.i32()?);
+					let _model_attributes = content.dict()?;
+				}
+				nodes.insert(node_id, SceneNode::Shape(shape_models));
+			}
+			b"LAYR" => {
+				let layer_id = content.i32()?;
+				let attributes = content.dict()?;
+				if attributes.get("_hidden").is_some_and(|hidden| hidden == "1") {
+					hidden_layers.push(layer_id);
+				}
+			}
+			// materials, cameras, render settings, etc
+			_ => {}
+		}
+	}
+
+	// collect every visible model instance with its final transform
+	let mut instances = vec![];
+	if nodes.is_empty() {
+		// files from before the scene graph existed just have the models, all at the origin
+		for model in 0..models.len() {
+			instances.push((model as i32, VoxTransform::IDENTITY));
+		}
+	} else {
+		// the scene graph is a tree, so a node showing up twice means a corrupt file that could loop forever
+		let mut visited = HashSet::new();
+		let mut stack = vec![(0, VoxTransform::IDENTITY)];
+		while let Some((node_id, parent_transform)) = stack.pop() {
+			if !visited.insert(node_id) {
+				return Err(VoxError::InvalidNode(node_id));
+			}
+			match nodes.get(&node_id).ok_or(VoxError::InvalidNode(node_id))? {
+				&SceneNode::Transform {
+					transform,
+					child,
+					layer,
+					hidden,
+				} => {
+					if !hidden && !hidden_layers.contains(&layer) {
+						stack.push((child, parent_transform.then(transform)));
+					}
+				}
+				SceneNode::Group(children) => {
+					stack.extend(children.iter().map(|&child| (child, parent_transform)));
+				}
+				SceneNode::Shape(shape_models) => {
+					instances.extend(shape_models.iter().map(|&model| (model, parent_transform)));
+				}
+			}
+		}
+	}
+
+	// place every voxel in scene space, which is z up like MagicaVoxel, then rotate to bevy's y up
+	let mut voxels = vec![];
+	for (model, transform) in instances {
+		let (size, model_voxels) = usize::try_from(model)
+			.ok()
+			.and_then(|idx| Some((*sizes.get(idx)?, models.get(idx)?)))
+			.ok_or(VoxError::InvalidModel(model))?;
+
+		for &(pos, id) in model_voxels {
+			// transforms are relative to the center of the model, so rotate voxel centers around it
+			let center = pos.as_vec3() + 0.5 - size.as_vec3() / 2.0;
+			let scene_pos = (transform.rotation * center + transform.translation).floor().as_ivec3();
+			voxels.push((IVec3::new(scene_pos.x, scene_pos.z, -scene_pos.y), id));
+		}
+	}
+
+	let min = voxels.iter().fold(IVec3::MAX, |min, &(pos, _)| min.min(pos));
+	let max = voxels.iter().fold(IVec3::MIN, |max, &(pos, _)| max.max(pos));
+	let size = if voxels.is_empty() {
+		2
+	} else {
+		// translations come straight from the file, so the scene can be wider than any octree
+		let extent = (0..3)
+			.map(|axis| max[axis].checked_sub(min[axis])?.checked_add(1))
+			.try_fold(0, |extent, axis_extent| Some(extent.max(axis_extent?)));
+		extent
+			.and_then(|extent| (extent as u32).checked_next_power_of_two())
+			.ok_or(VoxError::TooLarge)?
+			.max(2)
+	};
+
+	let mut octree = Octree::new(size);
+	for (pos, id) in voxels {
+		octree.set_voxel((pos - min).as_uvec3(), id as u32);
+	}
+
+	Ok(VoxModel { octree, palette })
+}
+
+#[derive(Default)]
+pub struct VoxLoader;
+impl AssetLoader for VoxLoader {
+	type Asset = VoxModel;
+	type Settings = ();
+	type Error = VoxError;
+
+	fn load<'a>(
+		&'a self,
+		reader: &'a mut Reader,
+		_settings: &'a (),
+		_load_context: &'a mut LoadContext,
+	) -> BoxedFuture<'a, Result<VoxModel, VoxError>> {
+		Box::pin(async move {
+			let mut bytes = vec![];
+			reader.read_to_end(&mut bytes).await?;
+			load_vox(&bytes)
+		})
+	}
+
+	fn extensions(&self) -> &[&str] {
+		&["vox"]
+	}
+}
+
+enum SceneNode {
+	Transform {
+		transform: VoxTransform,
+		child: i32,
+		layer: i32,
+		hidden: bool,
+	},
+	Group(Vec<i32>),
+	Shape(Vec<i32>),
+}
+
+#[derive(Clone, Copy)]
+struct VoxTransform {
+	rotation: Mat3,
+	translation: Vec3,
+}
+impl VoxTransform {
+	const IDENTITY: Self = Self {
+		rotation: Mat3::IDENTITY,
+		translation: Vec3::ZERO,
+	};
+
+	fn parse(frame: &HashMap<String, String>) -> Result<Self, VoxError> {
+		let rotation = match frame.get("_r") {
+			Some(rotation) => rotation
+				.parse()
+				.ok()
+				.and_then(parse_rotation)
+				.ok_or_else(|| VoxError::InvalidRotation(rotation.clone()))?,
+			None => Mat3::IDENTITY,
+		};
+
+		let translation = match frame.get("_t") {
+			Some(translation) => {
+				let coords = translation
+					.split_whitespace()
+					.map(|coord| coord.parse::<i32>())
+					.collect::<Result<Vec<_>, _>>()
+					.ok()
+					.filter(|coords| coords.len() == 3)
+					.ok_or_else(|| VoxError::InvalidTranslation(translation.clone()))?;
+				IVec3::from_slice(&coords).as_vec3()
+			}
+			None => Vec3::ZERO,
+		};
+
+		Ok(Self { rotation, translation })
+	}
+
+	/// Applies `self` to the result of `child`
+	fn then(self, child: Self) -> Self {
+		Self {
+			rotation: self.rotation * child.rotation,
+			translation: self.rotation * child.translation + self.translation,
+		}
+	}
+}
+
+/// Bits 0-1 and 2-3 are the column of the non-zero entry in the first and second rows, and bits 4-6 are the signs of
+/// the three rows
+fn parse_rotation(bits: u8) -> Option<Mat3> {
+	let first = (bits & 3) as usize;
+	let second = ((bits >> 2) & 3) as usize;
+	if first > 2 || second > 2 || first == second {
+		return None;
+	}
+	let third = 3 - first - second;
+
+	let mut rows = [Vec3::ZERO; 3];
+	for (row, column) in [first, second, third].into_iter().enumerate() {
+		let negative = bits & (1 << (row + 4)) != 0;
+		rows[row][column] = if negative { -1.0 } else { 1.0 };
+	}
+
+	Some(Mat3::from_cols(rows[0], rows[1], rows[2]).transpose())
+}
+
+/// The palette MagicaVoxel uses for files without an `RGBA` chunk: a 6x6x6 color cube followed by red, green, blue, and
+/// gray ramps
+fn default_palette() -> [Color; 256] {
+	const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+	const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+	let mut colors = vec![Color::NONE];
+	for r in CUBE {
+		for g in CUBE {
+			for b in CUBE {
+				colors.push(Color::rgb_u8(r, g, b));
+			}
+		}
+	}
+	// the last cube color is black, which the ramps already cover
+	colors.pop();
+	colors.extend(RAMP.map(|v| Color::rgb_u8(v, 0, 0)));
+	colors.extend(RAMP.map(|v| Color::rgb_u8(0, v, 0)));
+	colors.extend(RAMP.map(|v| Color::rgb_u8(0, 0, v)));
+	colors.extend(RAMP.map(|v| Color::rgb_u8(v, v, v)));
+
+	colors.try_into().unwrap()
+}
+
+struct ByteReader<'a>(&'a [u8]);
+impl<'a> ByteReader<'a> {
+	fn take(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+		if self.0.len() < len {
+			return Err(VoxError::UnexpectedEof);
+		}
+		let (bytes, rest) = self.0.split_at(len);
+		self.0 = rest;
+		Ok(bytes)
+	}
+
+	fn u32(&mut self) -> Result<u32, VoxError> {
+		Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+	}
+
+	fn i32(&mut self) -> Result<i32, VoxError> {
+		Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+	}
+
+	fn string(&mut self) -> Result<String, VoxError> {
+		let len = self.u32()?;
+		Ok(String::from_utf8_lossy(self.take(len as usize)?).into_owned())
+	}
+
+	fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+		let len = self.u32()?;
+		(0..len).map(|_| Ok((self.string()?, self.string()?))).collect()
+	}
+
+	/// Returns the chunk id, its content, and its children
+	fn chunk(&mut self) -> Result<(&'a [u8], ByteReader<'a>, ByteReader<'a>), VoxError> {
+		let id = self.take(4)?;
+		let content_len = self.u32()?;
+		let children_len = self.u32()?;
+		let content = ByteReader(self.take(content_len as usize)?);
+		let children = ByteReader(self.take(children_len as usize)?);
+		Ok((id, content, children))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ints(values: &[i32]) -> Vec<u8> {
+		values.iter().flat_map(|value| value.to_le_bytes()).collect()
+	}
+
+	fn dict(pairs: &[(&str, &str)]) -> Vec<u8> {
+		let mut bytes = ints(&[pairs.len() as i32]);
+		for string in pairs.iter().flat_map(|&(key, value)| [key, value]) {
+			bytes.extend(ints(&[string.len() as i32]));
+			bytes.extend(string.as_bytes());
+		}
+		bytes
+	}
+
+	fn chunk(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+		let mut bytes = id.to_vec();
+		bytes.extend(ints(&[content.len() as i32, 0]));
+		bytes.extend(content);
+		bytes
+	}
+
+	fn vox(chunks: &[Vec<u8>]) -> Vec<u8> {
+		let children = chunks.concat();
+		let mut bytes = b"VOX ".to_vec();
+		bytes.extend(ints(&[150]));
+		bytes.extend(b"MAIN");
+		bytes.extend(ints(&[0, children.len() as i32]));
+		bytes.extend(children);
+		bytes
+	}
+
+	/// A 2x2x2 model with the given voxels
+	fn model(voxels: &[[u8; 4]]) -> [Vec<u8>; 2] {
+		let mut xyzi = ints(&[voxels.len() as i32]);
+		xyzi.extend(voxels.concat());
+		[chunk(b"SIZE", &ints(&[2, 2, 2])), chunk(b"XYZI", &xyzi)]
+	}
+
+	fn transform(node_id: i32, child: i32, frame: &[(&str, &str)]) -> Vec<u8> {
+		let mut content = ints(&[node_id]);
+		content.extend(dict(&[]));
+		content.extend(ints(&[child, -1, 0, 1]));
+		content.extend(dict(frame));
+		chunk(b"nTRN", &content)
+	}
+
+	fn group(node_id: i32, children: &[i32]) -> Vec<u8> {
+		let mut content = ints(&[node_id]);
+		content.extend(dict(&[]));
+		content.extend(ints(&[children.len() as i32]));
+		content.extend(ints(children));
+		chunk(b"nGRP", &content)
+	}
+
+	fn shape(node_id: i32, model: i32) -> Vec<u8> {
+		let mut content = ints(&[node_id]);
+		content.extend(dict(&[]));
+		content.extend(ints(&[1, model]));
+		content.extend(dict(&[]));
+		chunk(b"nSHP", &content)
+	}
+
+	#[test]
+	fn models_without_scene_graph() {
+		let bytes = vox(&model(&[[0, 0, 0, 5], [1, 0, 1, 9]]));
+		let model = load_vox(&bytes).unwrap();
+
+		// z up becomes y up, and y becomes -z
+		assert_eq!(model.octree.size(), 2);
+		assert_eq!(model.octree.get_voxel(UVec3::new(0, 0, 0)), 5);
+		assert_eq!(model.octree.get_voxel(UVec3::new(1, 1, 0)), 9);
+		assert_eq!(model.octree.leaves().non_empty().count(), 2);
+		assert_eq!(model.palette[1], Color::rgb_u8(0xff, 0xff, 0xff));
+	}
+
+	#[test]
+	fn scene_graph_transforms() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.extend([
+			transform(0, 1, &[]),
+			group(1, &[2, 4]),
+			transform(2, 3, &[]),
+			shape(3, 0),
+			transform(4, 5, &[("_t", "10 0 0")]),
+			shape(5, 0),
+		]);
+		let model = load_vox(&vox(&chunks)).unwrap();
+
+		assert_eq!(model.octree.size(), 16);
+		assert_eq!(model.octree.get_voxel(UVec3::ZERO), 1);
+		assert_eq!(model.octree.get_voxel(UVec3::new(10, 0, 0)), 1);
+		assert_eq!(model.octree.leaves().non_empty().count(), 2);
+	}
+
+	#[test]
+	fn invalid_magic() {
+		let mut bytes = vox(&model(&[]));
+		bytes[0] = b'X';
+		assert!(matches!(load_vox(&bytes), Err(VoxError::InvalidMagic)));
+	}
+
+	#[test]
+	fn truncated() {
+		let bytes = vox(&model(&[[0, 0, 0, 1]]));
+		assert!(matches!(
+			load_vox(&bytes[..bytes.len() - 2]),
+			Err(VoxError::UnexpectedEof)
+		));
+	}
+
+	#[test]
+	fn missing_node() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.push(transform(0, 7, &[]));
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::InvalidNode(7))));
+	}
+
+	#[test]
+	fn missing_model() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.extend([transform(0, 1, &[]), shape(1, 3)]);
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::InvalidModel(3))));
+	}
+
+	#[test]
+	fn transform_cycle() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.push(transform(0, 0, &[]));
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::InvalidNode(0))));
+	}
+
+	#[test]
+	fn group_cycle() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.extend([
+			transform(0, 1, &[]),
+			group(1, &[2, 1]),
+			transform(2, 3, &[]),
+			shape(3, 0),
+		]);
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::InvalidNode(1))));
+	}
+
+	#[test]
+	fn too_large() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.extend([
+			transform(0, 1, &[]),
+			group(1, &[2, 4]),
+			transform(2, 3, &[("_t", "-2000000000 0 0")]),
+			shape(3, 0),
+			transform(4, 5, &[("_t", "2000000000 0 0")]),
+			shape(5, 0),
+		]);
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::TooLarge)));
+	}
+
+	#[test]
+	fn invalid_transform() {
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.push(transform(0, 1, &[("_r", "3")]));
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::InvalidRotation(r)) if r == "3"));
+
+		let mut chunks = model(&[[0, 0, 0, 1]]).to_vec();
+		chunks.push(transform(0, 1, &[("_t", "1 2")]));
+		assert!(matches!(load_vox(&vox(&chunks)), Err(VoxError::InvalidTranslation(t)) if t == "1 2"));
+	}
+
+	#[test]
+	fn rotations() {
+		// the first row's entry in column 0 and the second's in column 1, all positive
+		assert_eq!(parse_rotation(0b0000100), Some(Mat3::IDENTITY));
+		// 90 degrees around z: x goes to y and y goes to -x
+		let rotation = parse_rotation(0b0010001).unwrap();
+		assert_eq!(rotation * Vec3::X, Vec3::Y);
+		assert_eq!(rotation * Vec3::Y, -Vec3::X);
+		assert_eq!(rotation * Vec3::Z, Vec3::Z);
+		// every row has its sign flipped
+		assert_eq!(parse_rotation(0b1110100), Some(-Mat3::IDENTITY));
+
+		// two rows can't use the same column, and there are only three columns
+		assert_eq!(parse_rotation(0b0000000), None);
+		assert_eq!(parse_rotation(0b0000011), None);
+		assert_eq!(parse_rotation(0b0001100), None);
+	}
+
+	#[test]
+	fn palette() {
+		let palette = default_palette();
+		assert_eq!(palette[0], Color::NONE);
+		assert_eq!(palette[1], Color::rgb_u8(0xff, 0xff, 0xff));
+		assert_eq!(palette[215], Color::rgb_u8(0x00, 0x00, 0x33));
+		assert_eq!(palette[255], Color::rgb_u8(0x11, 0x11, 0x11));
+	}
+}
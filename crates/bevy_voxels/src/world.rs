@@ -0,0 +1,184 @@
+use crate::{ChunkData, ChunkSize, LoadedChunks};
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+/// Reads and edits voxels by their world position, across chunk borders
+///
+/// Only chunks that are loaded and have finished generating can be accessed. Edits are lost when a chunk is unloaded,
+/// since chunks are generated again from scratch when they're reloaded.
+#[derive(SystemParam)]
+pub struct VoxelWorld<'w, 's> {
+	loaded_chunks: Res<'w, LoadedChunks>,
+	chunk_size: Res<'w, ChunkSize>,
+	chunks: Query<'w, 's, &'static mut ChunkData>,
+}
+impl<'w, 's> VoxelWorld<'w, 's> {
+	/// Returns `None` if the chunk containing `pos` isn't loaded yet
+	pub fn get_voxel(&self, pos: IVec3) -> Option<u32> {
+		let (chunk_position, local_pos) = self.split_position(pos);
+		let entity = self.chunk_entity(chunk_position)?;
+		let data = self.chunks.get(entity).ok()?;
+		Some(data.0.get_voxel(local_pos))
+	}
+
+	/// Returns `false` and does nothing if the chunk containing `pos` isn't loaded yet
+	///
+	/// `id` must not be greater than [`MAX_VOXEL_ID`](crate::octree::MAX_VOXEL_ID)
+	pub fn set_voxel(&mut self, pos: IVec3, id: u32) -> bool {
+		let (chunk_position, local_pos) = self.split_position(pos);
+		let Some(entity) = self.chunk_entity(chunk_position) else {
+			return false;
+		};
+		let Ok(mut data) = self.chunks.get_mut(entity) else {
+			return false;
+		};
+
+		// only flag the chunk for upload if something actually changed
+		if data.0.get_voxel(local_pos) != id {
+			data.0.set_voxel(local_pos, id);
+		}
+		true
+	}
+
+	/// Finds the first non-empty voxel along a ray, within `max_dist` of `origin`. `dir` doesn't need to be normalized.
+	///
+	/// Chunks that aren't loaded yet are treated as empty. Returns `None` if `dir` is zero, if `origin` or `dir` aren't
+	/// finite, or if `max_dist` is NaN.
+	pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<VoxelRaycastHit> {
+		// the chunk walk only stops once it passes max_dist, which never happens if any of the t values are NaN
+		if dir.length_squared() == 0.0 || !dir.is_finite() || !origin.is_finite() || max_dist.is_nan() {
+			return None;
+		}
+		let dir = dir.normalize();
+		let parallel = dir.cmpeq(Vec3::ZERO);
+
+		// step through the chunks along the ray in order, so the first hit in any of them is the closest one
+		let chunk_size = **self.chunk_size as f32;
+		let mut chunk_position = (origin / chunk_size).floor().as_ivec3();
+		let step = IVec3::select(dir.cmpgt(Vec3::ZERO), IVec3::ONE, IVec3::NEG_ONE);
+		let exit_side = Vec3::select(dir.cmpgt(Vec3::ZERO), Vec3::ONE, Vec3::ZERO);
+		let t_delta = Vec3::select(parallel, Vec3::INFINITY, (chunk_size / dir).abs());
+		let mut t_next = (((chunk_position.as_vec3() + exit_side) * chunk_size) - origin) / dir;
+		t_next = Vec3::select(parallel, Vec3::INFINITY, t_next);
+
+		loop {
+			let chunk_origin = chunk_position * **self.chunk_size as i32;
+			let hit = self
+				.chunk_entity(chunk_position)
+				.and_then(|entity| self.chunks.get(entity).ok())
+				.and_then(|data| data.0.raycast(origin - chunk_origin.as_vec3(), dir, max_dist));
+			if let Some(hit) = hit {
+				return Some(VoxelRaycastHit {
+					pos: chunk_origin + hit.pos.as_ivec3(),
+					normal: hit.normal,
+					distance: hit.distance,
+					id: hit.id,
+				});
+			}
+
+			let t = t_next.min_element();
+			if t > max_dist {
+				return None;
+			}
+			let axis = if t_next.x == t {
+				0
+			} else if t_next.y == t {
+				1
+			} else {
+				2
+			};
+			chunk_position[axis] += step[axis];
+			t_next[axis] += t_delta[axis];
+		}
+	}
+
+	/// Splits a world position into the position of its chunk and its position inside that chunk
+	fn split_position(&self, pos: IVec3) -> (IVec3, UVec3) {
+		let chunk_size = IVec3::splat(**self.chunk_size as i32);
+		(pos.div_euclid(chunk_size), pos.rem_euclid(chunk_size).as_uvec3())
+	}
+
+	fn chunk_entity(&self, chunk_position: IVec3) -> Option<Entity> {
+		self.loaded_chunks.ready(chunk_position)
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelRaycastHit {
+	/// world position of the voxel that was hit
+	pub pos: IVec3,
+	/// normal of the face the ray entered the voxel through, or zero if the ray started inside it
+	pub normal: IVec3,
+	/// distance along the ray to the hit
+	pub distance: f32,
+	pub id: u32,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		octree::Octree,
+		tests::{chunk_world, spawn_ready_chunk},
+	};
+	use bevy::ecs::system::SystemState;
+	use std::sync::Arc;
+
+	/// A world with these chunks loaded, and the entities that keep them loaded
+	fn world(chunks: Vec<(IVec3, Octree)>) -> (World, Vec<Arc<Entity>>) {
+		let mut world = chunk_world();
+		let entities = chunks
+			.into_iter()
+			.map(|(chunk_position, octree)| spawn_ready_chunk(&mut world, chunk_position, octree))
+			.collect();
+		(world, entities)
+	}
+
+	fn chunk_with(pos: UVec3, id: u32) -> Octree {
+		let mut octree = Octree::new(8);
+		octree.set_voxel(pos, id);
+		octree
+	}
+
+	#[test]
+	fn raycast_across_chunks() {
+		let (mut world, _entities) = world(vec![
+			(IVec3::ZERO, Octree::new(8)),
+			(IVec3::new(-1, 0, 0), chunk_with(UVec3::new(2, 1, 1), 4)),
+			(IVec3::new(0, 0, 1), chunk_with(UVec3::new(3, 3, 5), 5)),
+		]);
+		let mut state = SystemState::<VoxelWorld>::new(&mut world);
+		let voxels = state.get_mut(&mut world);
+
+		let hit = voxels.raycast(Vec3::new(3.5, 1.5, 1.5), Vec3::NEG_X, 100.0).unwrap();
+		assert_eq!(hit.pos, IVec3::new(-6, 1, 1));
+		assert_eq!(hit.normal, IVec3::X);
+		assert_eq!(hit.distance, 8.5);
+		assert_eq!(hit.id, 4);
+
+		let hit = voxels.raycast(Vec3::new(3.5, 3.5, 0.5), Vec3::Z, 100.0).unwrap();
+		assert_eq!(hit.pos, IVec3::new(3, 3, 13));
+		assert_eq!(hit.id, 5);
+
+		// too short, and through chunks that aren't loaded
+		assert_eq!(voxels.raycast(Vec3::new(3.5, 1.5, 1.5), Vec3::NEG_X, 7.0), None);
+		assert_eq!(voxels.raycast(Vec3::new(3.5, 1.5, 1.5), Vec3::X, 100.0), None);
+	}
+
+	#[test]
+	fn raycast_invalid_rays() {
+		let (mut world, _entities) = world(vec![(IVec3::ZERO, chunk_with(UVec3::ZERO, 1))]);
+		let mut state = SystemState::<VoxelWorld>::new(&mut world);
+		let voxels = state.get_mut(&mut world);
+
+		let origin = Vec3::new(4.5, 0.5, 0.5);
+		assert!(voxels.raycast(origin, Vec3::NEG_X, 100.0).is_some());
+		assert_eq!(voxels.raycast(origin, Vec3::ZERO, 100.0), None);
+		assert_eq!(voxels.raycast(origin, Vec3::NAN, 100.0), None);
+		assert_eq!(
+			voxels.raycast(origin, Vec3::new(f32::NEG_INFINITY, 0.0, 0.0), 100.0),
+			None
+		);
+		assert_eq!(voxels.raycast(Vec3::NAN, Vec3::NEG_X, 100.0), None);
+		assert_eq!(voxels.raycast(origin, Vec3::NEG_X, f32::NAN), None);
+	}
+}
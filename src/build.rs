@@ -0,0 +1,101 @@
+use bevy::{prelude::*, window::CursorGrabMode};
+use bevy_voxels::{
+	generator::TerrainGenerator,
+	world::{VoxelRaycastHit, VoxelWorld},
+};
+
+use crate::{movement::window_controls, PrimaryCamera};
+
+/// how far away voxels can be edited from
+const REACH: f32 = 32.0;
+/// the voxels that can be placed, picked with the number keys
+const BLOCKS: [u32; 3] = [TerrainGenerator::STONE, TerrainGenerator::DIRT, TerrainGenerator::GRASS];
+
+/// Press B to toggle build mode. While it's on, left click breaks the voxel in the middle of the screen, and right
+/// click places one against the highlighted face.
+pub struct BuildPlugin;
+impl Plugin for BuildPlugin {
+	fn build(&self, app: &mut App) {
+		app.init_resource::<BuildMode>().add_systems(
+			Update,
+			(build_controls, target_voxel, edit_voxels, highlight_target)
+				.chain()
+				.before(window_controls),
+		);
+	}
+}
+
+#[derive(Default, Resource)]
+struct BuildMode {
+	enabled: bool,
+	/// index into `BLOCKS`
+	block: usize,
+	target: Option<VoxelRaycastHit>,
+}
+
+fn build_controls(mut build_mode: ResMut<BuildMode>, key: Res<ButtonInput<KeyCode>>) {
+	if key.just_pressed(KeyCode::KeyB) {
+		build_mode.enabled = !build_mode.enabled;
+		info!("build mode {}", if build_mode.enabled { "on" } else { "off" });
+	}
+
+	for (i, key_code) in [KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3]
+		.into_iter()
+		.enumerate()
+	{
+		if key.just_pressed(key_code) {
+			build_mode.block = i;
+		}
+	}
+}
+
+fn target_voxel(
+	mut build_mode: ResMut<BuildMode>,
+	voxel_world: VoxelWorld,
+	cameras: Query<&Transform, With<PrimaryCamera>>,
+	window: Query<&Window>,
+) {
+	// the cursor is hidden and the target is the middle of the screen, so only aim while it's grabbed
+	if !build_mode.enabled || window.single().cursor.grab_mode != CursorGrabMode::Locked {
+		build_mode.target = None;
+		return;
+	}
+
+	let camera_transform = cameras.single();
+	build_mode.target = voxel_world.raycast(camera_transform.translation, *camera_transform.forward(), REACH);
+}
+
+fn edit_voxels(build_mode: Res<BuildMode>, mut voxel_world: VoxelWorld, mouse: Res<ButtonInput<MouseButton>>) {
+	let Some(target) = build_mode.target else {
+		return;
+	};
+
+	if mouse.just_pressed(MouseButton::Left) {
+		voxel_world.set_voxel(target.pos, 0);
+	} else if mouse.just_pressed(MouseButton::Right) && target.normal != IVec3::ZERO {
+		voxel_world.set_voxel(target.pos + target.normal, BLOCKS[build_mode.block]);
+	}
+}
+
+fn highlight_target(build_mode: Res<BuildMode>, mut gizmos: Gizmos) {
+	let Some(target) = build_mode.target else {
+		return;
+	};
+
+	// grow the outline a little so it isn't hidden inside the voxel's faces
+	let center = target.pos.as_vec3() + 0.5;
+	gizmos.cuboid(
+		Transform::from_translation(center).with_scale(Vec3::splat(1.01)),
+		Color::WHITE,
+	);
+
+	if target.normal != IVec3::ZERO {
+		let normal = target.normal.as_vec3();
+		gizmos.rect(
+			center + normal * 0.51,
+			Quat::from_rotation_arc(Vec3::Z, normal),
+			Vec2::splat(0.8),
+			Color::YELLOW,
+		);
+	}
+}
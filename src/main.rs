@@ -1,7 +1,9 @@
+mod build;
 mod movement;
 
 use bevy::prelude::*;
-use bevy_voxels::{octree::Octree, ChunkLoader, VoxelRenderPlugin};
+use bevy_voxels::{octree::Octree, ChunkLoader, LoadShape, VoxelRenderPlugin};
+use build::BuildPlugin;
 use movement::FlycamPlugin;
 use std::env::set_var;
 
@@ -9,7 +11,7 @@ fn main() {
 	set_var("WGPU_BACKEND", "vulkan");
 
 	App::new()
-		.add_plugins((DefaultPlugins, FlycamPlugin, VoxelRenderPlugin))
+		.add_plugins((DefaultPlugins, FlycamPlugin, BuildPlugin, VoxelRenderPlugin::default()))
 		.add_systems(Startup, setup)
 		.run();
 }
@@ -34,7 +36,10 @@ fn setup(
 			..default()
 		},
 		PrimaryCamera,
-		ChunkLoader::radius(8),
+		ChunkLoader::new(LoadShape::Cylinder {
+			radius: 8,
+			vertical_radius: 3,
+		}),
 	));
 
 	// light
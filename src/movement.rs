@@ -13,7 +13,7 @@ impl Plugin for FlycamPlugin {
 	}
 }
 
-fn window_controls(
+pub fn window_controls(
 	mut commands: Commands,
 	mut window: Query<(Entity, &mut Window)>,
 	mouse: Res<ButtonInput<MouseButton>>,